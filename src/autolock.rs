@@ -0,0 +1,118 @@
+use std::io::{self, Write};
+use std::sync::mpsc::RecvTimeoutError;
+
+use crate::figlet::fig_header;
+use crate::keyfile::keyfile_for;
+use crate::myio::{myinput_timeout, set_prompt_timeout};
+use crate::recovery::{phrase_hint, read_password_or_shares};
+use crate::throttle::FailedAttempts;
+use crate::totp::{ask_code, totp_enabled, unix_now};
+use crate::vault::{Slot, Vault};
+
+/// Waits for the next command or message, locking the vault if none comes within
+/// its idle timeout.
+///
+/// When the timeout passes the data key is wiped from memory, the screen is cleared
+/// and the password is asked for. Once the vault is unlocked again `redraw` puts
+/// back whatever was on screen, such as the selected channel, and the wait starts over.
+///
+/// # Arguments
+///
+/// * `vault` - The unlocked vault, locked and unlocked again in place.
+/// * `redraw` - Called with the unlocked vault after the screen was cleared.
+///
+/// # Returns
+///
+/// The line that was entered, or `None` once stdin is closed. If stdin closes while
+/// the vault is locked it stays locked, so the caller must stop using it.
+///
+/// # Examples
+///
+/// ```
+/// while let Some(input) = idle_input(vault, |_| fig_header("Welcome back!")) {
+///     run(input.split(" ").collect(), vault);
+/// }
+/// ```
+pub fn idle_input(vault: &mut Vault, redraw: impl Fn(&Vault)) -> Option<String> {
+    loop {
+        match myinput_timeout("", vault.idle_timeout()) {
+            Ok(input) => return Some(input),
+            Err(RecvTimeoutError::Disconnected) => return None,
+            Err(RecvTimeoutError::Timeout) => {
+                if !lock_until_unlocked(vault) {
+                    return None;
+                }
+                redraw(vault);
+            }
+        }
+    }
+}
+
+/// Locks the vault and keeps asking for the password until it unlocks.
+///
+/// Wrong passwords are counted and slowed down the same way as at startup.
+///
+/// # Returns
+///
+/// `false` if stdin closed before the vault was unlocked, or the right password
+/// came after too many wrong ones and the vault was wiped.
+fn lock_until_unlocked(vault: &mut Vault) -> bool {
+    vault.lock();
+    // nothing is left to protect, the lock screen waits as long as it takes
+    set_prompt_timeout(None);
+    clear_screen();
+    fig_header("Locked");
+    println!("The vault was locked after being left idle.\n");
+
+    loop {
+        let mut attempts = match FailedAttempts::load() {
+            Ok(attempts) => attempts,
+            Err(err) => {
+                println!("error: could not read the failed attempt counter: {}", err);
+                return false;
+            }
+        };
+        attempts.wait_before_attempt();
+
+        let Some(password) = read_password_or_shares("Enter password to unlock:\n") else {
+            return false;
+        };
+        let keyfile = keyfile_for(&vault.header, &password);
+        let slot = match vault.unlock(&password, keyfile.as_ref()) {
+            Ok(slot) => slot,
+            Err(_) => {
+                attempts.wrong_password();
+                if let Some(hint) = phrase_hint(&password) {
+                    println!("{}", hint);
+                }
+                continue;
+            }
+        };
+        if attempts.wipe_if_over_limit(vault) {
+            vault.lock();
+            return false;
+        }
+        if slot == Slot::User && totp_enabled(vault) && !ask_code(vault, unix_now) {
+            vault.lock();
+            attempts.wrong_code();
+            continue;
+        }
+
+        clear_screen();
+        set_prompt_timeout(vault.idle_timeout());
+        match attempts.reset(vault) {
+            Ok(Some(notice)) => println!("{}\n", notice),
+            Ok(None) => {},
+            Err(err) => println!("error: could not reset the failed attempt counter: {}\n", err),
+        }
+        return true;
+    }
+}
+
+/// Clears the screen and its scrollback, and moves the cursor to the top.
+pub fn clear_screen() {
+    print!("{}[2J", 27 as char);
+    print!("{}[3J", 27 as char);
+    print!("{}[H", 27 as char);
+    io::stdout().flush().unwrap();
+}
@@ -1,62 +1,69 @@
+use crate::autolock::idle_input;
 use crate::figlet::fig_header;
-use crate::myio::myinput;
-use crate::password::{generate_master_password, update_password_file, get_hash};
+use crate::keyfile::{choose_keyfile, keyfile_for};
+use crate::manifest::{accept, record, verify, MANIFEST};
+use crate::myio::{myinput, read_password, set_prompt_timeout};
+use crate::strength::{choose_password, min_score};
+use crate::password::generate_master_password;
+use crate::mycrypto::read_encrypted_file;
+use crate::padlock::{is_locked, lock_document, open_document, read_channel, unlock_document, write_channel};
+use crate::recovery::{hand_out_shares, normalize_phrase, read_password_or_shares, show_master_password};
+use crate::rekey::{rekey, rotate};
+use crate::sealed::{clear_vault, other_index};
+use crate::shred::{delete_channel, read_stored, replace_encrypted_file, write_stored, KEY_TABLE};
+use crate::throttle::wipe_limit;
+use crate::totp::{accept_code, set_up_totp, totp_enabled, turn_off_totp, unix_now};
+use crate::vault::{new_data_key, Slot, Vault, VaultHeader};
 use std::collections::HashMap;
 use std::fs;
-use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::io::{ErrorKind, Write};
 
 use std::env;
 use std::path::{Path, PathBuf};
 
 /// Reads the names of the unhashed files from the mutable directory, the names
-/// are stored in contents.txt which are encrypted when program is not in use.
+/// are stored in contents.txt which is decrypted into memory, never onto the disk.
 ///
 /// The file path to contents.txt is obtained using the `get_path` function, 
 /// which returns the path of the file relative to the project root directory.
 ///
 /// # Panics
 ///
-/// This function will panic if the file cannot be opened or read.
+/// This function will panic if the file cannot be read or decrypted.
 ///
 /// # Examples
 ///
 /// ```
 /// // list is empty
-/// let mut new_list = get_contents();
+/// let mut new_list = get_contents(vault);
 ///
 /// new_list.push("this".to_owned());
 ///
 /// assert_eq!(new_list, ["this".to_owned()]);
 /// ```
-fn get_contents() -> Vec<String> {
-    let file_name = get_path("mutable/d1b2a59fbea7e20077af9f91b27e95e865061b270be03ff539ab3b73587882e8.txt");
-    let file = File::open(file_name).unwrap();
-    let reader = BufReader::new(file);
+fn get_contents(vault: &Vault) -> Vec<String> {
+    let file_name = vault.channel_path("contents");
+    let contents = read_encrypted_file(file_name, &vault.key)
+        .expect("Could not read table of contents");
 
-    let mut list = Vec::new();
-
-    for line in reader.lines() {
-        let item = line.unwrap();
-        list.push(item);
-    }
-
-    list
+    contents.lines().map(|line| line.to_owned()).collect()
 }
 
 
 /// lets you update the list of the names of the files in the mutable directory
 ///
 /// The file path is obtained using the `get_path` function, which returns the
-/// path of the file relative to the project root directory.
+/// path of the file relative to the project root directory. The list is encrypted
+/// before it is written.
 ///
 /// # Panics
 ///
-/// This function will panic if the file cannot be opened or read.
+/// This function will panic if the file cannot be written.
 /// 
 /// # Arguments
 /// 
-/// * `list` - &Vec<String> to set contents.txt to.
+/// * `list` - &[String] to set contents.txt to.
+/// * `vault` - The unlocked vault whose key encrypts the list.
 ///
 /// # Examples
 ///
@@ -66,27 +73,28 @@ fn get_contents() -> Vec<String> {
 ///     .map(|&s| s.to_string())
 ///     .collect();
 /// 
-/// set_contents(&list);
-/// assert_eq!(list, get_contents());
+/// set_contents(&list, vault);
+/// assert_eq!(list, get_contents(vault));
 ///
 /// // you can add to the list and update it too: 
 /// new_list.push("this".to_owned());
 ///
-/// set_contents(&new_list);
-/// assert_eq!(new_list, get_contents());
+/// set_contents(&new_list, vault);
+/// assert_eq!(new_list, get_contents(vault));
 ///
 /// ```
-fn set_contents(list: &Vec<String>) {
-    let file_name = get_path("mutable/d1b2a59fbea7e20077af9f91b27e95e865061b270be03ff539ab3b73587882e8.txt");
-    let file = File::create(file_name).unwrap();
-
-    file.set_len(0).unwrap(); // truncate the file to zero bytes
-    let mut writer = BufWriter::new(file);
+fn set_contents(list: &[String], vault: &mut Vault) {
+    let file_name = vault.channel_path("contents");
 
+    let mut contents = String::new();
     for item in list {
-        writer.write_all(item.as_bytes()).unwrap();
-        writer.write_all(b"\n").unwrap();
+        contents.push_str(item);
+        contents.push('\n');
     }
+
+    replace_encrypted_file(file_name, &vault.key, &contents)
+        .and_then(|_| record(vault, &["contents"]))
+        .expect("Could not write table of contents");
 }
 
 /// Returns a path to a file or directory relative to the project directory.
@@ -136,55 +144,82 @@ pub fn get_path(path: impl AsRef<Path>) -> PathBuf {
     project_dir.join(path)
 }
 
-/// Gets the file path from the given command attributes or prompts the user for it.
+/// Files the vault keeps under channel names of its own, which no channel can use.
+const RESERVED_NAMES: [&str; 2] = [KEY_TABLE, MANIFEST];
+
+/// Gets the channel name and file path from the given command attributes or prompts the user for it.
+///
+/// The function takes a reference to a vector of string slices called `given_attributes` and a string `command_name`, and returns the channel name and a `PathBuf` representing the file path. If the first element of `given_attributes` is an empty string, the function prompts the user for the intended file name. Otherwise, it uses the first element of `given_attributes` as the file name to construct the file path.
+///
+/// Either way the name is turned into the channel's keyed file name with `Vault::channel_path`, so no file name on disk gives the channel name away.
 ///
-/// The function takes a reference to a vector of string slices called `given_attributes` and a string `command_name`, and returns a `PathBuf` representing the file path. If the first element of `given_attributes` is an empty string, the function prompts the user for the intended file name. Otherwise, it uses the first element of `given_attributes` as the file name to construct the file path.
+/// The vault keeps files of its own under the same kind of names, see `RESERVED_NAMES`. A name typed at the prompt can contain spaces, so it could be one of them, and is refused.
 ///
 /// # Arguments
 ///
 /// * `given_attributes` - A reference to a vector of string slices containing the command attributes.
 /// * `command_name` - A string representing the name of the command being executed.
+/// * `vault` - The unlocked vault whose key the file names are derived from.
 ///
 /// # Returns
 ///
-/// A `PathBuf` representing the file path.
+/// The channel name, and a `PathBuf` representing the file path, or `None` if the name is reserved.
 ///
 /// # Example
 ///
 /// ```
 /// use std::path::PathBuf;
-/// use crate::get_path_from_attributes;
+/// use crate::get_channel_from_attributes;
 ///
 /// let attributes = vec!["filename.txt"];
 /// let command_name = "open";
-/// let file_path = get_path_from_attributes(&attributes, command_name);
-/// println!("The file path is {:?}", file_path);
+/// if let Some((name, file_path)) = get_channel_from_attributes(&attributes, command_name, vault) {
+///     println!("The file path of {} is {:?}", name, file_path);
+/// }
 /// ```
-fn get_path_from_attributes(given_attributes: &Vec<&str>, command_name:&str) -> PathBuf {
+fn get_channel_from_attributes(given_attributes: &[&str], command_name:&str, vault: &Vault) -> Option<(String, PathBuf)> {
     // read the contents.json
 
 
     // if attributes is empty
-    let file_path;
-    if given_attributes[0] == "" {
+    let name = if given_attributes[0].is_empty() {
         let input = myinput("What is the file name\n");
 
         
         println!("\x1b[2K\x1b[2A\x1b[2K\x1b[2A");
         println!("{command_name} {input}\n");
 
-        file_path = get_path(format!("{}/{}", "mutable", input));
+        input
     }else {
         // get <file name> from attributes
-        println!("");
-        let file_name = get_hash(given_attributes[0]);
-        file_path = get_path(format!("{}/{}.txt", "mutable", file_name)); 
+        println!();
+        given_attributes[0].to_owned()
     };
 
-    return file_path.to_owned();
+    if RESERVED_NAMES.contains(&name.as_str()) {
+        println!("\"{}\" is used by the vault itself, choose another name.\n", name);
+        return None;
+    }
+
+    let file_path = vault.channel_path(&name);
+    Some((name, file_path))
 }
 
-pub fn run(input: Vec<&str>) { 
+pub fn run(input: Vec<&str>, vault: &mut Vault) { 
+
+    /// Prints a channel's title as a header followed by its messages.
+    fn show_document(title: &str, document: &str) {
+        let contents: String = document
+            .lines()
+            .skip(1)
+            .collect::<Vec<&str>>()
+            .join("\n");
+
+        fig_header(title);
+        println!(
+           "{contents}\n"
+        );
+    }
 
     /// This function selects a file and allows the user to write to it.
     /// 
@@ -204,41 +239,69 @@ pub fn run(input: Vec<&str>) {
     /// The function prints the title of the selected file, then reads the contents of it and prints them in the terminal. 
     /// The user can then write messages to the file, which the function adds to it's contents. 
     /// 
+    /// If the vault locks itself while the user is idle, the document is shown again once it is unlocked.
+    ///
+    /// A channel put under its own password with `/lock` asks for it first, every time
+    /// it is selected. Its key is forgotten when the document is closed or the vault
+    /// locks itself, after which it has to be selected again.
+    ///
     /// The user can quit the file selection by typing "/quit" twice. The first time will write the quit message to the file,
     /// the second time will exit the selection.
-    fn select (attributes : Vec<&str>) { 
+    fn select (attributes : Vec<&str>, vault: &mut Vault) { 
         if attributes[0] == "contents" {
             println!("Cannot select contents file -> access directory from /files\n");
             return;
         }      
 
-        let file_path = get_path_from_attributes(&attributes, "/select");
+        let Some((title, _)) = get_channel_from_attributes(&attributes, "/select", vault) else { return };
             
-        println!("Opening document {}", format!(r#""{}""#, attributes[0]));
+        println!(r#"Opening document "{}""#, title);
         
-        let contents = match fs::read_to_string(&*file_path) {
-            Ok(contents) => contents,
+        let stored = match read_stored(vault, &title) {
+            Ok(document) => document,
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                println!("Could not open document: {}\n", err);
+                return;
+            }
             Err(err) => {
                 println!("How stupid do you feel for typing to open a file that doesn't fucking exist, I mean really, the ignorance you have to your own directory that you have been building with this app is fucking hilarious\ntry it again and don't fuck it up.\nalr?\nalr.\nOh and for good measure, here is your error you fucking half-wit: {}\nwow, oH My gOd, what a shocker, couldn't find the fucking file\nyou're a joke.\n", err); 
                 return;
             }
         };
+
+        // a locked channel asks for its own password every time it is selected
+        if is_locked(&stored) {
+            let Some(password) = read_password("This channel is locked, enter its password:\n") else { return };
+            match unlock_document(&stored, &password) {
+                Ok(key) => vault.channel_key = Some(key),
+                Err(err) => {
+                    println!("Could not open document: {}\n", err);
+                    return;
+                }
+            }
+        }
+        drop(stored);
+        let document = match read_channel(vault, &title) {
+            Ok(document) => document,
+            Err(err) => {
+                println!("Could not open document: {}\n", err);
+                vault.channel_key = None;
+                return;
+            }
+        };
         
         println!("Document opened.");
 
-        let title: String = attributes[0].to_owned();
-
-        let contents: String = contents
-            .lines()
-            .skip(1)
-            .collect::<Vec<&str>>()
-            .join("\n");
+        clear(vec![], vault);
+        show_document(&title, &document);
 
-        clear(vec![]);
-        fig_header(&title);
-        println!(
-           "{contents}\n"
-        );
+        // the document is read again for every message rather than kept in memory,
+        // so nothing stays decrypted while the vault is locked for being idle
+        drop(document);
+        let redraw = |vault: &Vault| match read_channel(vault, &title) {
+            Ok(document) => show_document(&title, &document),
+            Err(err) => println!("Could not open document: {}\n", err),
+        };
 
         let mut temp_message = {
             if attributes.len() > 1 {
@@ -250,8 +313,11 @@ pub fn run(input: Vec<&str>) {
         loop {
             // get next input
             let message:String = {
-                if &temp_message.len() > &0 {temp_message.to_owned()} else {
-                    myinput("")
+                if !temp_message.is_empty() {temp_message.to_owned()} else {
+                    match idle_input(vault, redraw) {
+                        Some(message) => message,
+                        None => break,
+                    }
                 }
             };
 
@@ -267,18 +333,19 @@ pub fn run(input: Vec<&str>) {
             let formatted_date_time = format!("{} ", now.format("%Y-%m-%d %H:%M:%S"));
             let final_message = formatted_date_time + &message;
             
-            // add message to selected file
-            let mut file = fs::OpenOptions::new()
-                .write(true)
-                .append(true)
-                .open(&*file_path)
-                .expect("Could not open file");
-
-            file.write_all(&format!("{}\n", final_message).as_bytes())
-                .expect("Could not write to file");
+            // add message to selected file, encrypted before it is written
+            let written = read_channel(vault, &title)
+                .and_then(|mut document| {
+                    document.push_str(&format!("{}\n", final_message));
+                    write_channel(vault, &title, &document)
+                });
+            if let Err(err) = written {
+                println!("Could not write to file: {}\n", err);
+                break;
+            }
 
             // rewrite in terminal
-            if temp_message == "" {
+            if temp_message.is_empty() {
                 print!("\x1b[2A");
                 println!("{final_message}\n\x1b[K");
             } else {
@@ -287,6 +354,7 @@ pub fn run(input: Vec<&str>) {
                 temp_message = ""
             }
         }
+        vault.channel_key = None;
         println!("Existed document.");
         println!("write quit one more time to exit terminal\n");
     }
@@ -317,11 +385,21 @@ pub fn run(input: Vec<&str>) {
     ///        /new           <file name>              - creates file of <file>
     ///        /files  (/dir)                          - lists all files in directory
     ///        /delete (/del) <file name>              - deletes file from directory
+    ///        /lock          <file name>              - puts a file under a password of its own
+    ///        /unlock        <file name>              - removes the password of a locked file
+    ///        /verify        [accept]                 - checks the files against the manifest, or trusts them as they are
     ///        /quit                                   - quits document or terminal
     ///        /clear                                  - clears terminal (not document)
     ///        /newpassword (/pass)                    - generates new master-pass / create new password
+    ///        /shares                                 - splits the master password into shares for several people
+    ///        /rotate-key                             - re-encrypts every file under a new key, the passwords stay the same
+    ///        /duress        [off]                    - sets a duress password that opens a second vault, or removes it
+    ///        /totp          [off]                    - asks for an authenticator app code at login, or stops asking
+    ///        /timeout       <minutes>                - locks the vault after <minutes> idle, 0 turns it off
+    ///        /wipeafter     <attempts>               - wipes the vault after <attempts> wrong passwords, 0 turns it off
+    ///        /minscore      <0-4>                    - the strength score new passwords need
     /// ```
-    fn help(_attributes : Vec<&str>) {
+    fn help(_attributes : Vec<&str>, _vault: &mut Vault) {
         // print help.txt to terminal
 
         println!("Opening document...");
@@ -364,8 +442,8 @@ pub fn run(input: Vec<&str>) {
     /// ```
     /// 
     /// Will create a new file called "my_file.txt" in the document directory.
-    fn new (attributes : Vec<&str>) {
-        let file_path = get_path_from_attributes(&attributes, "/new");
+    fn new (attributes : Vec<&str>, vault: &mut Vault) {
+        let Some((title, file_path)) = get_channel_from_attributes(&attributes, "/new", vault) else { return };
 
         // create a new file of name <file name> in document directory
         println!("Creating file...");
@@ -373,18 +451,16 @@ pub fn run(input: Vec<&str>) {
             println!("File already exists.\n");
             return;
         }
-        let _file = File::create(&*file_path)
+        write_stored(vault, &title, &format!("Title: {}\n", title))
             .expect("Error creating file"); 
         println!("File Created.\n");
-
-        let title:String = attributes[0].to_owned();
         
         // add title to contents.txt list
-        let mut list = get_contents();
+        let mut list = get_contents(vault);
         list.push(title);
-        set_contents(&list);
+        set_contents(&list, vault);
 
-        select(vec![attributes[0]]);
+        select(vec![attributes[0]], vault);
     }
 
     /// `files` is a function that prints the names of all files in the "mutable" directory.
@@ -411,20 +487,29 @@ pub fn run(input: Vec<&str>) {
     ///                document.txt
     ///                main.txt
     /// ```
-    fn files (_attributes : Vec<&str>) {
+    fn files (_attributes : Vec<&str>, vault: &mut Vault) {
 
         fig_header("Directory");
 
-        let file_names = get_contents();
+        let file_names = get_contents(vault);
         for file_name in file_names {
-            println!("{}", file_name);
+            let locked = read_stored(vault, &file_name)
+                .is_ok_and(|stored| is_locked(&stored));
+            match locked {
+                true => println!("{} (locked)", file_name),
+                false => println!("{}", file_name),
+            }
         }
 
-        println!("");
+        println!();
     }
 
     /// Deletes a file from a given path.
     ///
+    /// The channel is taken out of the table of contents, its own key is destroyed
+    /// and its file is overwritten with random bytes before it is removed, see
+    /// `shred.rs`.
+    ///
     /// # Parameters
     ///
     /// * `attributes` - A vector of strings representing the path of the file to delete.
@@ -443,17 +528,17 @@ pub fn run(input: Vec<&str>) {
     /// # Errors
     ///
     /// Returns an error if the file couldn't be removed.
-    fn delete (attributes: Vec<&str>) {
+    fn delete (attributes: Vec<&str>, vault: &mut Vault) {
         if attributes[0] == "contents" {
             println!("Delteing contents file will result in lost access to all of your files will remain unencrypted and only will be recoverable if you have a copy of the contents file and know how to replace it.");
         }
         let del = myinput("Are you sure you want to delete? (y/n)\n");
-        if !(del.to_lowercase() == "y") {
+        if del.to_lowercase() != "y" {
             println!("deltion cancelled\n");
             return;
         }
 
-        let file_path = get_path_from_attributes(&attributes, "/delete");
+        let Some((name, file_path)) = get_channel_from_attributes(&attributes, "/delete", vault) else { return };
 
         println!("Deleting...");
         if !Path::new(&file_path).exists() {
@@ -461,50 +546,533 @@ pub fn run(input: Vec<&str>) {
             return;
         }
 
-        fs::remove_file(file_path)
-            .expect("Couldn't remove file");
-        println!("Deleted file.\n");
+        // taken out of the contents first, so it is never listed without its file
+        let mut list = get_contents(vault);
+        list.retain(|listed| *listed != name);
+        if name != "contents" {
+            set_contents(&list, vault);
+        }
 
-        // remove from contents
-        let mut list = get_contents();
-        if let Some(index) = list.iter().position(|x| *x == attributes[0]) {
-            list.remove(index);
+        let had_key = delete_channel(vault, &name)
+            .expect("Couldn't remove file");
+        println!("Deleted file.");
+        match had_key {
+            true => println!("Its key and its file were overwritten with random bytes. An SSD may still keep old copies of both, which your password could open.\n"),
+            false => println!("It was overwritten, but it had no key of its own to destroy: copies the disk kept may still be readable with the vault's key.\n"),
         }
-        set_contents(&list);
 
+        if name == "contents" {
+            set_contents(&list, vault);
+        }
     }
 
-    /// Prompts the user for a new password and updates the password file.
+    /// Changes the login password and/or master password and re-keys the vault.
     ///
-    /// If the user chooses to generate a new master password, the master password file is
-    /// updated and the new master password is printed to the console. Otherwise,
-    /// the user is prompted for a new password, and the password file is updated with
-    /// the new password.
+    /// The current password is asked for first. A fresh data key is then generated,
+    /// wrapped under the new login and master passwords, and every channel is
+    /// re-encrypted under it with `rekey`, which switches the whole vault over in one
+    /// step. An interruption leaves the vault entirely on the old key or entirely on
+    /// the new one, never a mix.
+    ///
+    /// If the user chooses to generate a new master password, it is printed to the
+    /// console. Otherwise the existing master password is asked for so it can be
+    /// carried over, or a new one is generated if the user leaves it empty.
     ///
     /// # Examples
     ///
     /// ```
     /// let attributes: Vec<&str> = vec![];
-    /// new_password(attributes);
+    /// new_password(attributes, vault);
     /// ```
-    fn new_password(_attributes: Vec<&str>) {
+    fn new_password(_attributes: Vec<&str>, vault: &mut Vault) {
+        let Some(current) = read_password_or_shares("Enter your current password:\n") else { return };
+        let keyfile = keyfile_for(&vault.header, &current);
+        let slot = match vault.header.unlock(&current, keyfile.as_ref()) {
+            Ok((slot, key)) if key == vault.key => slot,
+            _ => {
+                println!("Wrong password.\n");
+                return;
+            }
+        };
+
         let gen = myinput("Do you want to generate new master password? (y/n)\n");
-        if gen == "y" {
-            println!("...\npassword saved successfully.");
-            println!("Here is your Master password {}\n", generate_master_password());
+        let (new_pass, master_password, is_new_master) = if gen == "y" {
+            let new_pass = match slot {
+                Slot::User => current,
+                Slot::Master => {
+                    let Some(new_pass) = choose_password("What will your new password be?\n", min_score(&vault.header)) else { return };
+                    new_pass
+                }
+            };
+            (new_pass, generate_master_password(), true)
+        } else {
+            let Some(new_pass) = choose_password("What will your new password be?\n", min_score(&vault.header)) else { return };
+            match slot {
+                Slot::Master => (new_pass, current, false),
+                Slot::User => {
+                    let Some(master) = read_password_or_shares("Enter your master password to keep it, or leave this empty to get a new one:\n") else { return };
+                    if master.is_empty() {
+                        (new_pass, generate_master_password(), true)
+                    } else if vault.header.unwrap_key(Slot::Master, &master, None).is_ok() {
+                        (new_pass, normalize_phrase(&master).unwrap_or(master), false)
+                    } else {
+                        println!("That is not the master password.\n");
+                        return;
+                    }
+                }
+            }
+        };
+
+        let new_keyfile = choose_keyfile(vault.header.get("user_keyfile"));
+
+        let new_key = new_data_key();
+        let mut header = vault.header.clone();
+        header.wrap_key(Slot::User, &new_pass, new_keyfile.as_ref(), &new_key)
+            .and_then(|_| header.wrap_key(Slot::Master, &master_password, None, &new_key))
+            .expect("Error updating vault header");
+
+        if let Err(err) = rekey(vault, header, new_key) {
+            println!("\nerror: {}", err);
+            println!("Your password has not been changed.\n");
             return;
         }
-        let new_pass = myinput("What will your new password be?\n");
-        update_password_file(&new_pass, false)
-            .expect("Error updating password file");
-        println!("...\npassword saved successfully.\n");
+
+        println!("...\npassword saved successfully.");
+        if is_new_master {
+            show_master_password(&master_password);
+        }
+        println!();
     }   
 
+    /// Replaces the vault's data key with a new one and re-encrypts every channel under it.
+    ///
+    /// The passwords stay the same, but both key slots have to be wrapped again, so
+    /// the login password and the master password are both asked for, whichever one
+    /// is typed first. See `rotate` for how an interruption is recovered from: the
+    /// rotation continues the next time the vault is unlocked.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// rotate_key(vec![""], vault);
+    /// ```
+    fn rotate_key(_attributes: Vec<&str>, vault: &mut Vault) {
+        match vault.header.get("key_rotated") {
+            Some(date) => println!("The data key was last rotated on {}.", date),
+            None => println!("The data key has not been rotated since the vault was created."),
+        }
+
+        let Some(current) = read_password_or_shares("Enter your current password:\n") else { return };
+        let keyfile = keyfile_for(&vault.header, &current);
+        let slot = match vault.header.unlock(&current, keyfile.as_ref()) {
+            Ok((slot, key)) if key == vault.key => slot,
+            _ => {
+                println!("Wrong password.\n");
+                return;
+            }
+        };
+
+        let (password, keyfile, master_password) = match slot {
+            Slot::User => {
+                let Some(master) = read_password_or_shares("Enter your master password:\n") else { return };
+                if vault.header.unwrap_key(Slot::Master, &master, None).is_err() {
+                    println!("That is not the master password.\n");
+                    return;
+                }
+                (current, keyfile, normalize_phrase(&master).unwrap_or(master))
+            },
+            Slot::Master => {
+                let Some(password) = read_password("Enter your login password:\n") else { return };
+                let keyfile = keyfile_for(&vault.header, &password);
+                if vault.header.unwrap_key(Slot::User, &password, keyfile.as_ref()).is_err() {
+                    println!("That is not the login password.\n");
+                    return;
+                }
+                (password, keyfile, normalize_phrase(&current).unwrap_or(current))
+            },
+        };
+
+        let new_key = new_data_key();
+        let mut slots = VaultHeader::new();
+        slots.wrap_key(Slot::User, &password, keyfile.as_ref(), &new_key)
+            .and_then(|_| slots.wrap_key(Slot::Master, &master_password, None, &new_key))
+            .expect("Error updating vault header");
+
+        match rotate(vault, slots, new_key) {
+            Ok(()) => println!("...\nEvery channel is now encrypted under a new data key.\n"),
+            Err(err) => {
+                println!("\nerror: {}", err);
+                println!("The rotation will continue the next time the vault is unlocked.\n");
+            }
+        }
+    }
+
+    /// Puts a channel under a password of its own.
+    ///
+    /// The channel stays encrypted under the vault's key, and inside that it is
+    /// encrypted again under a key derived from its password, see `padlock.rs`. `/select`
+    /// asks for the password before it opens the channel, and `/files` lists it as
+    /// locked. Nothing else opens it, not even the Master password.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// lock(vec!["diary"], vault);
+    /// ```
+    fn lock(attributes: Vec<&str>, vault: &mut Vault) {
+        let Some((name, _)) = get_channel_from_attributes(&attributes, "/lock", vault) else { return };
+        if name.is_empty() || name == "contents" {
+            println!("usage: /lock <file name>\n");
+            return;
+        }
+        let stored = match read_stored(vault, &name) {
+            Ok(stored) => stored,
+            Err(err) => {
+                println!("Could not open document: {}\n", err);
+                return;
+            }
+        };
+        if is_locked(&stored) {
+            println!("{} is already locked, `/unlock {}` removes its password.\n", name, name);
+            return;
+        }
+
+        let Some(password) = choose_password(&format!("Choose a password for {}:\n", name), min_score(&vault.header)) else { return };
+        match lock_document(&stored, &password).and_then(|locked| write_stored(vault, &name, &locked)) {
+            Ok(()) => println!("{} is locked. If you forget its password it can't be opened again, not even with the Master password.\n", name),
+            Err(err) => println!("error: {}\n", err),
+        }
+    }
+
+    /// Removes the password a channel was given with `/lock`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// unlock(vec!["diary"], vault);
+    /// ```
+    fn unlock(attributes: Vec<&str>, vault: &mut Vault) {
+        let Some((name, _)) = get_channel_from_attributes(&attributes, "/unlock", vault) else { return };
+        if name.is_empty() || name == "contents" {
+            println!("usage: /unlock <file name>\n");
+            return;
+        }
+        let stored = match read_stored(vault, &name) {
+            Ok(stored) => stored,
+            Err(err) => {
+                println!("Could not open document: {}\n", err);
+                return;
+            }
+        };
+        if !is_locked(&stored) {
+            println!("{} is not locked.\n", name);
+            return;
+        }
+
+        let Some(password) = read_password(&format!("Enter the password of {}:\n", name)) else { return };
+        let unlocked = unlock_document(&stored, &password)
+            .and_then(|key| open_document(&stored, &key))
+            .and_then(|document| write_stored(vault, &name, &document));
+        match unlocked {
+            Ok(()) => println!("{} no longer has a password of its own.\n", name),
+            Err(err) => println!("error: {}\n", err),
+        }
+    }
+
+    /// Sets up or removes the duress password, which opens a second vault.
+    ///
+    /// Typed at the login prompt, the duress password opens a separate vault with
+    /// its own channels instead of this one. The two are sealed side by side into
+    /// `vault.bin` and work the same way, so nothing on disk or in the app shows
+    /// which one was set up first, or whether there is a second one at all, see
+    /// `sealed.rs`. That also means `/duress` does the same from either of them:
+    /// setting one up replaces the other vault, `/duress off` destroys it.
+    ///
+    /// The second vault gets a Master password of its own, shown like the first one's.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// duress(vec![""], vault);    // choose a duress password, creating a second vault
+    /// duress(vec!["off"], vault); // destroy the other vault
+    /// ```
+    fn duress(attributes: Vec<&str>, vault: &mut Vault) {
+        match attributes[0] {
+            "" | "on" => {
+                let sure = myinput("The duress password opens a second, empty vault instead of this one. This replaces the second vault if there already is one. Continue? (y/n)\n");
+                if sure.to_lowercase() != "y" {
+                    println!("Nothing changed.\n");
+                    return;
+                }
+                let Some(password) = choose_password("Choose the duress password:\n", min_score(&vault.header)) else { return };
+                let keyfile = keyfile_for(&vault.header, &password);
+                if vault.header.unlock(&password, keyfile.as_ref()).is_ok() {
+                    println!("That password opens this vault, the duress password has to be a different one.\n");
+                    return;
+                }
+
+                match Vault::create(&password, None, other_index(vault.header.index)) {
+                    Ok((_, master_password)) => {
+                        println!("The duress password is set. Log in with it to fill the second vault with something believable.");
+                        println!("The second vault has a Master password of its own:");
+                        show_master_password(&master_password);
+                        println!();
+                    },
+                    Err(err) => println!("error: {}\n", err),
+                }
+            },
+            "off" => {
+                let sure = myinput("Destroy the second vault and its channels for good? (y/n)\n");
+                if sure.to_lowercase() != "y" {
+                    println!("Nothing changed.\n");
+                    return;
+                }
+                match clear_vault(other_index(vault.header.index)) {
+                    Ok(()) => println!("The duress password no longer opens anything.\n"),
+                    Err(err) => println!("error: {}\n", err),
+                }
+            },
+            _ => println!("usage: /duress [off]\n"),
+        }
+    }
+
+    /// Splits the Master password into shares for several people.
+    ///
+    /// Asks for the Master password (or enough of its shares) first, then how many
+    /// shares to make and how many are needed to unlock. The Master password itself
+    /// doesn't change, so earlier shares keep working, but they can't be mixed with
+    /// the new ones.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// shares(vec![""], vault);
+    /// ```
+    fn shares(_attributes: Vec<&str>, vault: &mut Vault) {
+        let Some(master) = read_password_or_shares("Enter your master password:\n") else { return };
+        let master = normalize_phrase(&master).unwrap_or(master);
+        if vault.header.unwrap_key(Slot::Master, &master, None).is_err() {
+            println!("That is not the master password.\n");
+            return;
+        }
+
+        hand_out_shares(&master);
+    }
+
+    /// Turns authenticator codes (TOTP) at login on or off.
+    ///
+    /// `/totp` sets them up: a QR code is shown for an authenticator app, and codes
+    /// are asked for after the password from then on. `/totp off` needs a current code,
+    /// or the master password for when the phone is lost.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// authenticator(vec![""], vault);    // set up, or show whether codes are on
+    /// authenticator(vec!["off"], vault); // stop asking for codes
+    /// ```
+    fn authenticator(attributes: Vec<&str>, vault: &mut Vault) {
+        match attributes[0] {
+            "" | "on" if totp_enabled(vault) => println!("Authenticator codes are on, `/totp off` turns them off.\n"),
+            "" | "on" => match set_up_totp(vault, unix_now) {
+                Ok(()) => println!("Authenticator codes are on, the next login asks for one after the password.\n"),
+                Err(err) => println!("error: {}\n", err),
+            },
+            "off" if !totp_enabled(vault) => println!("Authenticator codes are already off.\n"),
+            "off" => {
+                let Some(answer) = read_password_or_shares("Enter a code from your authenticator app, or your master password if you lost it:\n") else { return };
+                let allowed = if answer.len() == 6 && answer.bytes().all(|b| b.is_ascii_digit()) {
+                    accept_code(vault, &answer, unix_now()).unwrap_or(false)
+                } else {
+                    vault.header.unwrap_key(Slot::Master, &answer, None).is_ok()
+                };
+                if !allowed {
+                    println!("That is neither a current code nor the master password.\n");
+                    return;
+                }
+
+                match turn_off_totp(vault) {
+                    Ok(()) => println!("Authenticator codes are off.\n"),
+                    Err(err) => println!("error: {}\n", err),
+                }
+            },
+            _ => println!("usage: /totp [off]\n"),
+        }
+    }
+
+    /// Shows or changes how long the vault may sit idle before it locks itself.
+    ///
+    /// The timeout is stored in the vault header, so it applies to every session.
+    /// `0` turns auto-lock off.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// timeout(vec!["10"], vault); // lock after ten minutes
+    /// timeout(vec![""], vault);   // prints the current timeout
+    /// ```
+    fn timeout(attributes: Vec<&str>, vault: &mut Vault) {
+        if attributes[0].is_empty() {
+            match vault.idle_timeout() {
+                Some(timeout) => println!("The vault locks after {} minute(s) idle.\n", timeout.as_secs() / 60),
+                None => println!("Auto-lock is off.\n"),
+            }
+            return;
+        }
+
+        let minutes: u64 = match attributes[0].parse() {
+            Ok(minutes) => minutes,
+            Err(_) => {
+                println!("usage: /timeout <minutes>, 0 turns auto-lock off\n");
+                return;
+            }
+        };
+
+        vault.header.set("idle_timeout", minutes.saturating_mul(60));
+        if let Err(err) = vault.header.save(&vault.key) {
+            println!("error: {}\n", err);
+            return;
+        }
+        set_prompt_timeout(vault.idle_timeout());
+
+        match minutes {
+            0 => println!("Auto-lock turned off.\n"),
+            _ => println!("The vault will lock after {} minute(s) idle.\n", minutes),
+        }
+    }
+
+    /// Shows or changes how many wrong passwords in a row wipe the vault.
+    ///
+    /// The limit is sealed in the vault header, so it can't be changed without the key
+    /// and only applies to this vault, see `FailedAttempts::wipe_if_over_limit`. `0`
+    /// turns wiping off, which is the default.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// wipe_after(vec!["10"], vault); // wipe after ten wrong passwords
+    /// wipe_after(vec![""], vault);   // prints the current limit
+    /// ```
+    fn wipe_after(attributes: Vec<&str>, vault: &mut Vault) {
+        if attributes[0].is_empty() {
+            match wipe_limit(&vault.header) {
+                limit if limit > 0 => println!("The vault is wiped after {} wrong password(s) in a row.\n", limit),
+                _ => println!("The vault is never wiped after wrong passwords.\n"),
+            }
+            return;
+        }
+
+        let limit: u32 = match attributes[0].parse() {
+            Ok(limit) => limit,
+            Err(_) => {
+                println!("usage: /wipeafter <attempts>, 0 turns wiping off\n");
+                return;
+            }
+        };
+        if limit > 0 {
+            let sure = myinput(&format!("After {} wrong password(s) in a row every channel will be destroyed for good. Are you sure? (y/n)\n", limit));
+            if sure.to_lowercase() != "y" {
+                println!("Nothing changed.\n");
+                return;
+            }
+        }
+
+        match limit {
+            0 => vault.header.remove("wipe_after"),
+            _ => vault.header.set("wipe_after", limit),
+        }
+        if let Err(err) = vault.header.save(&vault.key) {
+            println!("error: {}\n", err);
+            return;
+        }
+
+        match limit {
+            0 => println!("The vault will never be wiped after wrong passwords.\n"),
+            _ => println!("The vault will be wiped after {} wrong password(s) in a row.\n", limit),
+        }
+    }
+
+    /// Shows or changes the strength score new passwords need.
+    ///
+    /// Scores go from 0 (very weak) to 4 (very strong), the default is 3. A password
+    /// below it can still be kept by typing `use it anyway` when it is refused. The
+    /// score is stored in the vault header.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// min_password_score(vec!["4"], vault); // only accept very strong passwords
+    /// min_password_score(vec![""], vault);  // prints the current score
+    /// ```
+    fn min_password_score(attributes: Vec<&str>, vault: &mut Vault) {
+        if attributes[0].is_empty() {
+            println!("New passwords need a strength score of at least {} out of 4.\n", min_score(&vault.header));
+            return;
+        }
+
+        let score: u8 = match attributes[0].parse() {
+            Ok(score) if score <= 4 => score,
+            _ => {
+                println!("usage: /minscore <0-4>, 0 accepts any password\n");
+                return;
+            }
+        };
+
+        vault.header.set("min_password_score", score);
+        if let Err(err) = vault.header.save(&vault.key) {
+            println!("error: {}\n", err);
+            return;
+        }
+
+        println!("New passwords now need a strength score of at least {} out of 4.\n", score);
+    }
+
+    /// Checks the vault's files against the integrity manifest, or records them as they are.
+    ///
+    /// The manifest lists a hash of every channel, see `manifest.rs`, and is also
+    /// checked every time the vault is unlocked. `accept` trusts the files as they
+    /// are now, for after a problem was looked into or a file was restored on purpose.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// verify(vec![""], vault);       // lists missing, modified and rolled back files
+    /// verify(vec!["accept"], vault); // records every file as it is now
+    /// ```
+    fn verify_files(attributes: Vec<&str>, vault: &mut Vault) {
+        match attributes[0] {
+            "" => {},
+            "accept" => {
+                match accept(vault) {
+                    Ok(()) => println!("The manifest now records every file as it is.\n"),
+                    Err(err) => println!("error: {}\n", err),
+                }
+                return;
+            },
+            _ => {
+                println!("usage: /verify [accept]\n");
+                return;
+            }
+        }
+
+        match verify(vault) {
+            Ok(problems) if problems.is_empty() => println!("All files match the manifest.\n"),
+            Ok(problems) => {
+                println!("The vault's files don't match its manifest:");
+                for problem in problems {
+                    println!("  {}", problem);
+                }
+                println!("Type `/verify accept` to trust the files as they are now.\n");
+            },
+            Err(err) => println!("error: {}\n", err),
+        }
+    }
+
     /// Clears the terminal screen.
     ///
     /// This function clears the terminal screen by sending ANSI escape codes to the
     /// console.
-    fn clear (_attributes: Vec<&str>) {
+    fn clear (_attributes: Vec<&str>, _vault: &mut Vault) {
         print!("{}[2J", 27 as char);
         print!("{}[H", 27 as char);
         std::io::stdout().flush().unwrap();
@@ -520,7 +1088,7 @@ pub fn run(input: Vec<&str>) {
     }
 
     let commands =  {
-        let mut h: HashMap<String, fn(Vec<&str>)> = HashMap::new();
+        let mut h: HashMap<String, fn(Vec<&str>, &mut Vault)> = HashMap::new();
 
         h.insert(String::from("/select"), select);
         h.insert(String::from("/sel"), select);
@@ -535,16 +1103,30 @@ pub fn run(input: Vec<&str>) {
         h.insert(String::from("/help"), help);
         h.insert(String::from("/new"), new);
 
+        h.insert(String::from("/lock"), lock);
+        h.insert(String::from("/unlock"), unlock);
+        h.insert(String::from("/verify"), verify_files);
+
         h.insert(String::from("/newpassword"), new_password);
         h.insert(String::from("/pass"), new_password);
 
+        h.insert(String::from("/rotate-key"), rotate_key);
+        h.insert(String::from("/shares"), shares);
+        h.insert(String::from("/duress"), duress);
+
+        h.insert(String::from("/totp"), authenticator);
+
+        h.insert(String::from("/timeout"), timeout);
+        h.insert(String::from("/wipeafter"), wipe_after);
+        h.insert(String::from("/minscore"), min_password_score);
+
         h // returns h
     };
 
     if commands.contains_key(command) {
-        commands[command](attributes);
+        commands[command](attributes, vault);
     }else if commands.contains_key(&format!("/{}", command)) {
-        commands[&format!("/{}", command)](attributes);
+        commands[&format!("/{}", command)](attributes, vault);
     }else {
         println!("\ninvalid command, use `/help` to list commands.\n")
     }
@@ -0,0 +1,191 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::myio::myinput;
+use crate::recovery::normalize_phrase;
+use crate::secret::SecretBytes;
+use crate::vault::VaultHeader;
+
+/// Size of a keyfile made by `Keyfile::create`, in bytes.
+const NEW_KEYFILE_LEN: usize = 64;
+
+/// A file whose contents are needed besides the password to open the user key slot.
+///
+/// Any file works, such as a random one made for it on a USB stick: only a SHA256
+/// hash of its contents is kept, and it is mixed into the key derived from the
+/// password, so the password alone no longer unwraps the data key. The path is
+/// remembered in the header (`user_keyfile`) so the file can be found again, the
+/// hash is never stored.
+///
+/// The Master password doesn't need the keyfile, it stays the way back in if the
+/// keyfile is lost.
+///
+/// # Examples
+///
+/// ```
+/// let keyfile = Keyfile::load("/media/usb/vault.key")?;
+/// header.wrap_key(Slot::User, &password, Some(&keyfile), &data_key)?;
+/// ```
+pub struct Keyfile {
+    pub path: String,
+    hash: SecretBytes,
+}
+
+impl Keyfile {
+    /// Reads a keyfile and hashes its contents.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be read or is empty.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Keyfile> {
+        let path = fs::canonicalize(path)?;
+        let contents = SecretBytes::new(fs::read(&path)?);
+        if contents.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "the keyfile is empty"));
+        }
+
+        let hash = SecretBytes::new(Sha256::digest(&contents).to_vec());
+        Ok(Keyfile { path: path.to_string_lossy().into_owned(), hash })
+    }
+
+    /// Writes a new keyfile of random bytes and loads it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `AlreadyExists` error rather than overwriting a file that is there.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Keyfile> {
+        let mut contents = SecretBytes::new(vec![0u8; NEW_KEYFILE_LEN]);
+        rand::thread_rng().fill_bytes(&mut contents);
+
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        file.write_all(&contents)?;
+        file.sync_all()?;
+
+        Keyfile::load(path)
+    }
+
+    /// Mixes the keyfile into a key derived from the password.
+    ///
+    /// # Returns
+    ///
+    /// HMAC-SHA256 of the keyfile's hash under the derived key.
+    pub fn mix_into(&self, kek: &[u8]) -> SecretBytes {
+        let mut mac = Hmac::<Sha256>::new_from_slice(kek).expect("HMAC accepts keys of any length");
+        mac.update(&self.hash);
+        SecretBytes::new(mac.finalize().into_bytes().to_vec())
+    }
+}
+
+/// Finds the keyfile needed to unlock with `password`, if any.
+///
+/// Nothing is needed if the vault has no keyfile, or `password` is a recovery
+/// phrase. Otherwise the keyfile is read from the path in the header, and if it
+/// isn't there the user is asked where it is.
+///
+/// # Returns
+///
+/// The keyfile, or `None` if none is needed or the user skipped it, in which case
+/// only the Master password can unlock.
+///
+/// # Examples
+///
+/// ```
+/// let keyfile = keyfile_for(&header, &password);
+/// let (slot, key) = header.unlock(&password, keyfile.as_ref())?;
+/// ```
+pub fn keyfile_for(header: &VaultHeader, password: &str) -> Option<Keyfile> {
+    let path = header.get("user_keyfile")?;
+    if normalize_phrase(password).is_some() {
+        return None;
+    }
+
+    keyfile_at(path)
+}
+
+/// Reads the keyfile at `path`, and if it isn't there asks the user where it is.
+///
+/// # Returns
+///
+/// The keyfile, or `None` if the user skipped it.
+///
+/// # Examples
+///
+/// ```
+/// let keyfile = open.keyfile_path.as_deref().and_then(keyfile_at);
+/// let key = open.data_key(keyfile.as_ref())?;
+/// ```
+pub fn keyfile_at(path: &str) -> Option<Keyfile> {
+    let mut result = Keyfile::load(path);
+    loop {
+        match result {
+            Ok(keyfile) => return Some(keyfile),
+            Err(err) => println!("The keyfile could not be read: {}", err),
+        }
+        let path = myinput("Enter the path of your keyfile, or leave this empty to skip it:\n");
+        if path.is_empty() {
+            return None;
+        }
+        result = Keyfile::load(path);
+    }
+}
+
+/// Asks which keyfile, if any, should be needed besides a new password.
+///
+/// A path where no file exists can get a new random keyfile made there.
+///
+/// # Arguments
+///
+/// * `current` - The path of the keyfile in use now, `None` if there is none.
+///
+/// # Returns
+///
+/// The chosen keyfile, or `None` for password only.
+///
+/// # Examples
+///
+/// ```
+/// let keyfile = choose_keyfile(vault.header.get("user_keyfile"));
+/// header.wrap_key(Slot::User, &new_pass, keyfile.as_ref(), &new_key)?;
+/// ```
+pub fn choose_keyfile(current: Option<&str>) -> Option<Keyfile> {
+    loop {
+        let path = match current {
+            Some(current) => {
+                let answer = myinput(&format!("Keyfile: leave this empty to keep {}, type `none` to stop using one, or enter the path of another:\n", current));
+                match answer.as_str() {
+                    "" => current.to_owned(),
+                    "none" => return None,
+                    _ => answer,
+                }
+            },
+            None => {
+                let answer = myinput("Enter the path of a keyfile to need besides the password (for example on a USB stick), or leave this empty for none:\n");
+                if answer.is_empty() {
+                    return None;
+                }
+                answer
+            }
+        };
+
+        let result = if Path::new(&path).exists() {
+            Keyfile::load(&path)
+        } else if myinput(&format!("There is no file at {}, create a new random keyfile there? (y/n)\n", path)).to_lowercase() == "y" {
+            Keyfile::create(&path)
+        } else {
+            continue;
+        };
+
+        match result {
+            Ok(keyfile) => {
+                println!("The vault will need {} to unlock with your password. Keep a copy of it, without it only the Master password opens the vault.", keyfile.path);
+                return Some(keyfile);
+            },
+            Err(err) => println!("error: {}", err),
+        }
+    }
+}
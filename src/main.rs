@@ -1,12 +1,37 @@
 mod terminal;
+mod autolock;
 mod commands;
 mod figlet;
+mod keyfile;
+mod manifest;
+mod migrate;
 mod myio;
 mod mycrypto;
+mod padlock;
 mod password;
-use crate::password::{update_password_file, check_password, generate_master_password, new_key};
-use crate::mycrypto::{decrypt_files, encrypt_files};
+mod recovery;
+mod rekey;
+mod sealed;
+mod secret;
+mod session;
+mod shred;
+mod shutdown;
+mod strength;
+mod throttle;
+mod totp;
+mod vault;
+use crate::keyfile::{choose_keyfile, keyfile_at};
+use crate::manifest::verify;
+use crate::recovery::{phrase_hint, read_password_or_shares, show_master_password};
+use crate::rekey::{finish_rekey, resume_rotation};
+use crate::sealed::{find_slot, random_index, read_vault_file, vault_file_exists};
+use crate::session::{recover, SessionLock};
+use crate::throttle::FailedAttempts;
+use crate::totp::{ask_code, totp_enabled, unix_now};
+use crate::password::password_files_cleared;
+use crate::vault::{channel_state, ChannelState, Slot, Vault, VaultHeader, FORMAT_VERSION};
 use crate::myio::myinput;
+use crate::strength::{choose_password, DEFAULT_MIN_SCORE};
 use crate::commands::get_path;
 use std::fs;
 
@@ -59,47 +84,240 @@ fn set_conpty(enabled: bool, print_status: bool) {
     }
 }
 
+/// Creates a new vault, after the user confirms they want one.
+///
+/// Only called when there is no vault file and `mutable/` holds no channels,
+/// so it can never run over an existing vault.
+fn init_vault() -> Option<Vault> {
+    let answer = myinput("No vault found. Do you want to create a new one? (y/n)\n");
+    if answer.to_lowercase() != "y" {
+        println!("No vault created.");
+        return None;
+    }
+
+    let password = choose_password("Choose a password:\n", DEFAULT_MIN_SCORE)?;
+    let keyfile = choose_keyfile(None);
+    let (vault, master_password) = match Vault::create(&password, keyfile.as_ref(), random_index()) {
+        Ok(created) => created,
+        Err(err) => {
+            println!("error: {}", err);
+            return None;
+        }
+    };
+    println!("...\npassword saved successfully.");
+    show_master_password(&master_password);
+
+    Some(vault)
+}
+
+/// Asks for a password and unlocks the vault in `vault.bin` it opens.
+///
+/// The password is tried against the key slots of both vaults, see `find_slot`,
+/// and the one it opens decides which vault this is. The duress password opens
+/// the second vault the same way, with the same messages. If the slot needs a
+/// keyfile, it is read from the path the slot remembers.
+///
+/// Wrong passwords are counted, and once there have been a few every attempt has
+/// to wait longer than the last, see `throttle.rs`. If there have been as many as
+/// the vault's `/wipeafter` limit, it is wiped instead of opened.
+fn unlock_vault() -> Option<Vault> {
+    let data = match read_vault_file() {
+        Ok(data) => data,
+        Err(err) => {
+            println!("error: {}", err);
+            return None;
+        }
+    };
+    let mut attempts = match FailedAttempts::load() {
+        Ok(attempts) => attempts,
+        Err(err) => {
+            println!("error: could not read the failed attempt counter: {}", err);
+            return None;
+        }
+    };
+    attempts.wait_before_attempt();
+
+    let user_inputed_password = read_password_or_shares("Enter password:\n")?;
+
+    let Some(open) = find_slot(&data, &user_inputed_password) else {
+        attempts.wrong_password();
+        if let Some(hint) = phrase_hint(&user_inputed_password) {
+            println!("{}", hint);
+        }
+        return None;
+    };
+    let keyfile = open.keyfile_path.as_deref().and_then(keyfile_at);
+    let key = match open.data_key(keyfile.as_ref()) {
+        Ok(key) => key,
+        Err(_) => {
+            attempts.wrong_password();
+            println!("This vault needs its keyfile besides the password, check that it is the right file.");
+            return None;
+        }
+    };
+    let header = match VaultHeader::open(&data, open.index, &key) {
+        Ok(header) => header,
+        Err(err) => {
+            println!("error: {}, refusing to open the vault.", err);
+            return None;
+        }
+    };
+    match header.require_number::<u32>("format") {
+        Ok(format) if format > FORMAT_VERSION => {
+            println!("This vault is at format {}, which is newer than this version of cognitive-canvas understands (format {}).", format, FORMAT_VERSION);
+            return None;
+        },
+        Ok(_) => {},
+        Err(err) => {
+            println!("error: {}", err);
+            return None;
+        }
+    }
+    let mut vault = Vault { header, key, channel_key: None };
+    if attempts.wipe_if_over_limit(&vault) {
+        return None;
+    }
+    println!("Correct password.");
+
+    if totp_enabled(&vault) {
+        if open.slot == Slot::Master {
+            println!("Unlocked with the Master password, no authenticator code needed.");
+        } else if !ask_code(&mut vault, unix_now) {
+            attempts.wrong_code();
+            return None;
+        }
+    }
+    match attempts.reset(&mut vault) {
+        Ok(Some(notice)) => println!("{}", notice),
+        Ok(None) => {},
+        Err(err) => println!("error: could not reset the failed attempt counter: {}", err)
+    }
+
+    Some(vault)
+}
+
+/// Tells the user a vault in an older format has to be migrated before it can be opened.
+fn needs_migration(format: u32) {
+    println!("This vault is at format {}, this version of cognitive-canvas needs format {}.", format, FORMAT_VERSION);
+    println!("Run `cognitive-canvas migrate --dry-run` to see what would change, then `cognitive-canvas migrate` to upgrade it.");
+    println!("The original vault is backed up before anything is changed.");
+}
+
 fn main () {
     // Enable conpty feature
     // this is necessary to let lines be removed with println!
     set_conpty(true,false);
 
-    let password_file_path = get_path("Immutable/Shapassword.txt");
-
-    let user_inputed_password = myinput("Enter password:\n");
-
-    let metadata = fs::metadata(password_file_path).expect("Failed to get file metadata");
-    let is_empty = metadata.len() == 0;
-
-    if is_empty {
-        update_password_file(user_inputed_password.as_str(), false)
-            .unwrap_or_else(|e| eprintln!("Error updating password: {}", e));
-        println!("...\npassword saved successfully.");
-        println!("Here is your Master password {}", generate_master_password());
-    }else {
-        let pass:bool = check_password(user_inputed_password.as_str());
-        match pass {
-            true => println!("\x1B[2A\x1B[0GCorrect password."),
-            false => {
-                println!("Wrong password.");
-                return;
-            },
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let migrate_dry_run = match args.iter().map(|arg| arg.as_str()).collect::<Vec<&str>>().as_slice() {
+        [] => None,
+        ["migrate"] => Some(false),
+        ["migrate", "--dry-run"] => Some(true),
+        _ => {
+            println!("usage: cognitive-canvas [migrate [--dry-run]]");
+            return;
+        }
+    };
+
+    // the marker is removed when `_lock` drops, if it is still there at the next
+    // launch the app was killed with the vault open
+    let (_lock, stale_session) = match SessionLock::acquire() {
+        Ok(acquired) => acquired,
+        Err(err) => {
+            println!("error: {}", err);
+            return;
         }
+    };
+    if let Err(err) = shutdown::close_on_exit() {
+        println!("error: could not install signal handlers: {}", err);
+        return;
+    }
+    if let Some(previous) = &stale_session {
+        println!("The previous session ({}) did not close cleanly, the vault will be checked once it is unlocked.", previous);
     }
+    // silent, the password hasn't been entered yet, see `finish_rekey`
+    if let Err(err) = finish_rekey() {
+        println!("error: could not finish an interrupted re-key: {}", err);
+        return;
+    }
+    fs::create_dir_all(get_path("mutable")).expect("Failed to create mutable directory");
 
-    let key = new_key("my password");
-    match decrypt_files("mutable", &key.as_str()) {
-        Ok(_) => println!("Decrypt files successfully."),
-        Err(err) => println!("error: {}", err)
+    if let Some(dry_run) = migrate_dry_run {
+        migrate::run(dry_run);
+        return;
     }
 
-    terminal::main();
+    // only a vault with no vault file and no channels at all counts as new, an
+    // emptied password file is never taken as a first run
+    let vault = if vault_file_exists() {
+        if !password_files_cleared() {
+            println!("An upgrade of this vault to format {} was interrupted, run `cognitive-canvas migrate` to finish it.", FORMAT_VERSION);
+            None
+        } else {
+            unlock_vault()
+        }
+    } else {
+        match channel_state() {
+            Ok(ChannelState::Empty) => init_vault(),
+            Ok(ChannelState::Legacy) => {
+                needs_migration(0);
+                None
+            },
+            Ok(ChannelState::Sealed) => {
+                println!("mutable/ holds encrypted channels but Immutable/vault.bin is missing.");
+                println!("Refusing to create a new vault over them, restore the vault file from a backup.");
+                None
+            },
+            Err(err) => {
+                println!("error: {}", err);
+                None
+            }
+        }
+    };
+    let mut vault = match vault {
+        Some(vault) => vault,
+        None => return,
+    };
+
+    match recover(&vault) {
+        Ok(recovered) if recovered.is_empty() => {
+            if stale_session.is_some() {
+                println!("Nothing needed recovering.");
+            }
+        },
+        Ok(recovered) => {
+            println!("Recovered the vault from an unfinished session:");
+            for line in recovered {
+                println!("  {}", line);
+            }
+        },
+        Err(err) => println!("error: could not check the vault: {}", err)
+    }
 
-    match encrypt_files(get_path("mutable").to_str().unwrap(), &key.as_str()){
-        Ok(_) => println!("Encrypted files successfully."),
-        Err(err) => println!("error: {}", err)
+    match resume_rotation(&mut vault) {
+        Ok(true) => println!("Finished rotating the data key."),
+        Ok(false) => {},
+        Err(err) => println!("error: could not finish rotating the data key: {}", err)
     }
 
+    match verify(&mut vault) {
+        Ok(problems) if problems.is_empty() => {},
+        Ok(problems) => {
+            println!("The vault's files don't match its manifest:");
+            for problem in problems {
+                println!("  {}", problem);
+            }
+            println!("Someone may have changed, deleted or put back old copies of these files.");
+            println!("If the app was closed in the middle of a write this can also be a false alarm,");
+            println!("type `/verify accept` to trust the files as they are now.");
+        },
+        Err(err) => println!("error: could not check the manifest: {}", err)
+    }
+
+    // channels are decrypted into memory as they are opened, nothing on disk is
+    // ever plaintext so there is nothing to encrypt again on the way out
+    terminal::main(&mut vault);
+
     // Disable conpty feature
     set_conpty(false,false);
 }
\ No newline at end of file
@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+
+use sha2::{Digest, Sha256};
+
+use crate::commands::get_path;
+use crate::mycrypto::{decrypt_bytes, read_encrypted_file, write_encrypted_file};
+use crate::shred::KEY_TABLE;
+use crate::vault::Vault;
+
+/// The manifest, kept like a channel under the data key. No channel can be named
+/// this, see `get_channel_from_attributes`.
+pub const MANIFEST: &str = "integrity manifest";
+
+/// The manifest entry of the help file, which lives outside `mutable/`.
+const HELP_ENTRY: &str = "file Immutable/help.txt";
+
+/// Earlier hashes kept for each file, to tell a rolled back file from a modified one.
+const HISTORY: usize = 16;
+
+/// A list of every file of the vault with the hash of what it holds.
+///
+/// Stored encrypted under the data key like a channel (the encryption is
+/// authenticated, so nobody without the key can change it), as a `counter=` line
+/// and then one line per file: its hashes, newest first and comma separated, a
+/// space, and the channel name. The newest hash is the file as it should be, the
+/// older ones are versions it had before.
+///
+/// Every write to a channel updates its entry and raises the counter, which is
+/// also kept in the vault header as `manifest_counter`. So at unlock a deleted
+/// or swapped channel file shows up as missing or modified, an old copy of a
+/// channel put back shows up as rolled back, and an old copy of the whole manifest
+/// has a counter that doesn't match the header's.
+///
+/// Channels are hashed after taking off the data key's encryption, so a re-key
+/// doesn't change them. Only files the vault knows are covered: the chaff and the
+/// other vault's files in `mutable/` aren't, see `sealed.rs`.
+struct Manifest {
+    counter: u64,
+    files: BTreeMap<String, Vec<String>>,
+}
+
+impl Manifest {
+    /// Reads the manifest, `None` if the vault doesn't have one yet.
+    fn load(vault: &Vault) -> io::Result<Option<Manifest>> {
+        let text = match read_encrypted_file(vault.channel_path(MANIFEST), &vault.key) {
+            Ok(text) => text,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let damaged = || io::Error::new(ErrorKind::InvalidData, "the manifest is damaged");
+        let mut lines = text.lines();
+        let counter = lines.next()
+            .and_then(|line| line.strip_prefix("counter="))
+            .and_then(|counter| counter.parse().ok())
+            .ok_or_else(damaged)?;
+
+        let mut files = BTreeMap::new();
+        for line in lines.filter(|line| !line.is_empty()) {
+            let (hashes, name) = line.split_once(' ').ok_or_else(damaged)?;
+            files.insert(name.to_owned(), hashes.split(',').map(|hash| hash.to_owned()).collect());
+        }
+
+        Ok(Some(Manifest { counter, files }))
+    }
+
+    /// Raises the counter and writes the manifest, then records the counter in the header.
+    fn save(&mut self, vault: &mut Vault) -> io::Result<()> {
+        self.counter += 1;
+        let mut text = format!("counter={}\n", self.counter);
+        for (name, hashes) in &self.files {
+            text.push_str(&format!("{} {}\n", hashes.join(","), name));
+        }
+        write_encrypted_file(vault.channel_path(MANIFEST), &vault.key, &text)?;
+
+        vault.header.set("manifest_counter", self.counter);
+        vault.header.save(&vault.key)
+    }
+
+    /// Sets the hash a file should have now, keeping the one it had before.
+    fn set(&mut self, name: &str, hash: String) {
+        let hashes = self.files.entry(name.to_owned()).or_default();
+        if hashes.first() != Some(&hash) {
+            hashes.insert(0, hash);
+            hashes.truncate(HISTORY + 1);
+        }
+    }
+}
+
+/// Where a manifest entry's file is.
+fn entry_path(vault: &Vault, name: &str) -> PathBuf {
+    match name {
+        HELP_ENTRY => get_path("Immutable/help.txt"),
+        _ => vault.channel_path(name),
+    }
+}
+
+/// Hashes what a file holds, `Ok(None)` if it is missing.
+///
+/// # Errors
+///
+/// Returns an `InvalidData` error if a channel doesn't decrypt under the data key,
+/// which means it was modified or swapped for another vault's file.
+fn hash_entry(vault: &Vault, name: &str) -> io::Result<Option<String>> {
+    let data = match fs::read(entry_path(vault, name)) {
+        Ok(data) => data,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let hash = match name {
+        HELP_ENTRY => Sha256::digest(&data),
+        _ => Sha256::digest(&decrypt_bytes(&vault.key, &data)?),
+    };
+    Ok(Some(hex::encode(hash)))
+}
+
+/// Every file the manifest should cover: the channels in the table of contents, the
+/// table itself, the channel key table and the help file.
+fn known_entries(vault: &Vault) -> io::Result<Vec<String>> {
+    let mut names = vec!["contents".to_owned(), KEY_TABLE.to_owned(), HELP_ENTRY.to_owned()];
+    match read_encrypted_file(vault.channel_path("contents"), &vault.key) {
+        Ok(contents) => names.extend(contents.lines().skip(1).filter(|line| !line.is_empty()).map(|line| line.to_owned())),
+        Err(err) if err.kind() == ErrorKind::NotFound => {},
+        Err(err) => return Err(err),
+    }
+    Ok(names)
+}
+
+/// Updates the manifest after channels were written or deleted.
+///
+/// A vault that has no manifest yet starts one here with every file it knows.
+///
+/// # Arguments
+///
+/// * `vault` - The unlocked vault.
+/// * `names` - The channels that changed.
+///
+/// # Examples
+///
+/// ```
+/// write_encrypted_file(vault.channel_path("contents"), &vault.key, &contents)?;
+/// record(vault, &["contents"])?;
+/// ```
+pub fn record(vault: &mut Vault, names: &[&str]) -> io::Result<()> {
+    let mut manifest = match Manifest::load(vault)? {
+        Some(manifest) => manifest,
+        None => return accept(vault),
+    };
+
+    for name in names {
+        match hash_entry(vault, name)? {
+            Some(hash) => manifest.set(name, hash),
+            None => {
+                manifest.files.remove(*name);
+            }
+        }
+    }
+    manifest.save(vault)
+}
+
+/// Records every file the vault knows as it is now, trusting whatever changed.
+///
+/// Files that don't decrypt under the data key are left out of the manifest.
+pub fn accept(vault: &mut Vault) -> io::Result<()> {
+    let mut manifest = match Manifest::load(vault) {
+        Ok(Some(manifest)) => manifest,
+        _ => Manifest { counter: vault.header.get("manifest_counter").and_then(|counter| counter.parse().ok()).unwrap_or(0), files: BTreeMap::new() },
+    };
+
+    let mut files = BTreeMap::new();
+    for name in known_entries(vault)? {
+        if let Ok(Some(hash)) = hash_entry(vault, &name) {
+            let mut hashes = manifest.files.remove(&name).unwrap_or_default();
+            hashes.retain(|old| *old != hash);
+            hashes.insert(0, hash);
+            hashes.truncate(HISTORY + 1);
+            files.insert(name, hashes);
+        }
+    }
+    manifest.files = files;
+    manifest.save(vault)
+}
+
+/// Checks every file against the manifest.
+///
+/// # Returns
+///
+/// A line for every problem: a missing, modified or rolled back file, a channel
+/// the manifest doesn't list, or a manifest that is missing or older than the
+/// header. Empty if everything matches. A vault without a manifest yet gets one,
+/// with the files as they are.
+///
+/// # Examples
+///
+/// ```
+/// for problem in verify(vault)? {
+///     println!("  {}", problem);
+/// }
+/// ```
+pub fn verify(vault: &mut Vault) -> io::Result<Vec<String>> {
+    let expected: Option<u64> = vault.header.get("manifest_counter").and_then(|counter| counter.parse().ok());
+    let Some(manifest) = Manifest::load(vault)? else {
+        if expected.is_some() {
+            return Ok(vec!["the manifest is missing, it was deleted".to_owned()]);
+        }
+        accept(vault)?;
+        return Ok(Vec::new());
+    };
+
+    let mut problems = Vec::new();
+    match expected {
+        Some(expected) if expected == manifest.counter => {},
+        // the app was closed between writing the manifest and the header
+        Some(expected) if expected + 1 == manifest.counter => {
+            vault.header.set("manifest_counter", manifest.counter);
+            vault.header.save(&vault.key)?;
+        },
+        Some(expected) => problems.push(format!("the manifest was rolled back (version {}, the vault header expects {})", manifest.counter, expected)),
+        None => problems.push("the vault header has no record of the manifest, it was rolled back".to_owned()),
+    }
+
+    let display = |name: &str| name.strip_prefix("file ").unwrap_or(name).to_owned();
+    for (name, hashes) in &manifest.files {
+        match hash_entry(vault, name) {
+            Ok(None) => problems.push(format!("{}: missing", display(name))),
+            Ok(Some(hash)) if hashes.first() == Some(&hash) => {},
+            Ok(Some(hash)) if hashes.contains(&hash) => problems.push(format!("{}: rolled back to an earlier version", display(name))),
+            Ok(Some(_)) | Err(_) => problems.push(format!("{}: modified", display(name))),
+        }
+    }
+    for name in known_entries(vault)? {
+        if !manifest.files.contains_key(&name) && entry_path(vault, &name).exists() {
+            problems.push(format!("{}: not in the manifest", display(&name)));
+        }
+    }
+
+    Ok(problems)
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use crate::commands::get_path;
+use crate::myio::{myinput, read_password};
+use crate::mycrypto::{check_legacy_files, convert_legacy_files, read_legacy_file};
+use crate::password::{clear_password_files, generate_master_password, legacy_key, password_files_cleared, password_matches};
+use crate::recovery::show_master_password;
+use crate::rekey::{commit_vault_file, new_staging_dir, swap_dirs};
+use crate::sealed::{random_index, random_vault_file, vault_file_exists, write_chaff};
+use crate::session::LOCK_PATH;
+use crate::shred::shred_dir;
+use crate::vault::{channel_file_name, channel_state, legacy_channel_path, new_data_key, new_key_id, ChannelState, Slot, VaultHeader, FORMAT_VERSION};
+
+/// What the migration from format 0 does.
+const STEP: &str = "re-encrypt the channels from AES-128-ECB under the fixed key to AES-256-GCM under a new data key, \
+    in files named with keys from it, and seal the header and key slots into vault.bin";
+
+/// Where backups of the vault are written before a migration, relative to the project directory.
+const BACKUP_DIR: &str = "backups";
+
+/// Upgrades a format 0 vault in the project directory to `FORMAT_VERSION`.
+///
+/// What will happen is listed first, then the login password is asked for. Before
+/// anything is changed, `Immutable/` and `mutable/` are copied to `backups/<time>/`.
+/// The channels are converted into the staging directory a re-key uses, see
+/// `rekey.rs`, and writing `vault.bin` switches the vault over in one step. A
+/// migration interrupted before that is rolled back at the next launch and can be
+/// run again, one interrupted after it is finished then, except for emptying the
+/// password files, which running it again does.
+///
+/// # Arguments
+///
+/// * `dry_run` - Only check the password and report what would be done, writing nothing.
+///
+/// # Examples
+///
+/// ```
+/// // cognitive-canvas migrate --dry-run
+/// migrate::run(true);
+/// ```
+pub fn run(dry_run: bool) {
+    let format = match current_format() {
+        Ok(Some(format)) => format,
+        Ok(None) => {
+            println!("There is no vault here to migrate.");
+            return;
+        },
+        Err(err) => {
+            println!("error: {}", err);
+            return;
+        }
+    };
+
+    if format == FORMAT_VERSION {
+        if password_files_cleared() {
+            println!("The vault is already at format {}, there is nothing to migrate.", FORMAT_VERSION);
+            return;
+        }
+        if dry_run {
+            println!("The upgrade to format {} was interrupted, the old password files would be emptied.", FORMAT_VERSION);
+            return;
+        }
+        match clear_password_files() {
+            Ok(()) => println!("Finished the upgrade to format {}, the old password files were emptied.", FORMAT_VERSION),
+            Err(err) => println!("error: {}", err),
+        }
+        return;
+    }
+
+    println!("The vault is at format {}, the current format is {}.", format, FORMAT_VERSION);
+    println!("  {} -> {}: {}", format, FORMAT_VERSION, STEP);
+    if dry_run {
+        println!("Dry run, nothing will be written.");
+    }
+
+    let Some(password) = read_password("Enter your login password:\n") else { return };
+    if !password_matches(&password, false) {
+        println!("error: wrong password, the migration needs the login password rather than the master password, nothing was changed.");
+        return;
+    }
+
+    if dry_run {
+        if let Err(err) = dry_run_step() {
+            println!("error: {}", err);
+        }
+        return;
+    }
+
+    let backup = match backup_vault() {
+        Ok(backup) => backup,
+        Err(err) => {
+            println!("error: could not back up the vault, nothing was changed: {}", err);
+            return;
+        }
+    };
+    println!("Backed up the vault to {}", backup.display());
+
+    println!("Migrating from format {} to {}...", format, FORMAT_VERSION);
+    if let Err(err) = seal_legacy_vault(&password) {
+        println!("error: {}", err);
+        println!("The vault was left at format {}, the original is in {}.", format, backup.display());
+        return;
+    }
+
+    println!("The vault is now at format {}.", FORMAT_VERSION);
+    offer_to_shred_backup(&backup);
+}
+
+/// Offers to shred the backup once the migration has succeeded.
+///
+/// A backup of a format 0 vault holds every channel encrypted under the fixed key
+/// all old versions shared, which anyone can decrypt, so it is as good as plaintext.
+fn offer_to_shred_backup(backup: &Path) {
+    println!("The backup in {} holds your channels under the fixed key old versions used,", backup.display());
+    println!("anyone who gets hold of it can read them.");
+    let answer = myinput("Overwrite and delete the backup now? Keep it if you want to check the migrated vault first. (y/n)\n");
+    if answer.to_lowercase() != "y" {
+        println!("The backup was kept, shred or delete it yourself once you no longer need it.");
+        return;
+    }
+
+    match shred_dir(backup) {
+        Ok(()) => println!("The backup was overwritten and deleted."),
+        Err(err) => println!("error: could not shred the backup: {}", err),
+    }
+}
+
+/// Works out which format the vault is in.
+///
+/// # Returns
+///
+/// `None` if there is no vault at all: no vault file and no channels.
+fn current_format() -> io::Result<Option<u32>> {
+    if vault_file_exists() {
+        return Ok(Some(FORMAT_VERSION));
+    }
+    match channel_state()? {
+        ChannelState::Empty => Ok(None),
+        ChannelState::Legacy => Ok(Some(0)),
+        ChannelState::Sealed => Err(io::Error::new(
+            ErrorKind::NotFound,
+            "mutable/ holds encrypted channels but Immutable/vault.bin is missing, restore it from a backup",
+        )),
+    }
+}
+
+/// Converts a format 0 vault and seals it into a new `vault.bin`.
+///
+/// A new data key is wrapped under the login password and a new Master password,
+/// the channels are re-encrypted under it into the staging directory, with the file
+/// names keyed from it, and a few chaff files are added, as for a new vault. Writing
+/// `vault.bin` then switches the vault over, and only after that are the password
+/// files emptied, so a migration that stops half way can always be run again.
+///
+/// The new Master password is shown at the end, the old one no longer works.
+fn seal_legacy_vault(password: &str) -> io::Result<()> {
+    let key = new_data_key();
+    let master_password = generate_master_password();
+
+    // the table of contents names the channels, so their files can be renamed
+    let mut names = HashMap::new();
+    for name in ["contents"].into_iter().chain(legacy_contents()?.lines()) {
+        names.insert(OsString::from(legacy_channel_path(name).file_name().unwrap_or_default()), channel_file_name(&key, name));
+    }
+
+    let staging = new_staging_dir()?;
+    let staged = convert_legacy_files("mutable", &staging, &legacy_key(), &key, &names)
+        .and_then(|_| write_chaff(&staging));
+    if let Err(err) = staged {
+        let _ = shred_dir(&staging);
+        return Err(err);
+    }
+
+    let mut header = VaultHeader::new();
+    header.index = random_index();
+    header.wrap_key(Slot::User, password, None, &key)?;
+    header.wrap_key(Slot::Master, &master_password, None, &key)?;
+    header.set("format", FORMAT_VERSION);
+    header.set("key_id", new_key_id());
+    commit_vault_file(&header.seal_onto(random_vault_file(), &key)?)?;
+
+    swap_dirs()?;
+    clear_password_files()?;
+
+    println!("Your old master password no longer works.");
+    show_master_password(&master_password);
+    Ok(())
+}
+
+/// Reports what the migration would do.
+fn dry_run_step() -> io::Result<()> {
+    println!("The password is correct.");
+
+    let (report, failed) = check_legacy_files("mutable", &legacy_key())?;
+    for line in report {
+        println!("  {}", line);
+    }
+    if failed > 0 {
+        println!("  {} file(s) could not be converted, the migration would stop and change nothing.", failed);
+    }
+    println!("  a new Master password would be made, the old one would stop working");
+    println!("  vault.bin would be written and the password files emptied");
+
+    Ok(())
+}
+
+/// Reads the table of contents of a vault still at format 0.
+fn legacy_contents() -> io::Result<String> {
+    let path = legacy_channel_path("contents");
+    match path.exists() {
+        true => read_legacy_file(path, &legacy_key()),
+        false => Ok(String::new()),
+    }
+}
+
+/// Copies `Immutable/` and `mutable/` into a new directory under `backups/`.
+///
+/// # Returns
+///
+/// The directory the backup was written to.
+fn backup_vault() -> io::Result<PathBuf> {
+    let stamp = chrono::Local::now().format("%Y-%m-%d_%H-%M-%S").to_string();
+    let mut backup = get_path(format!("{}/{}", BACKUP_DIR, stamp));
+    let mut attempt = 1;
+    while backup.exists() {
+        attempt += 1;
+        backup = get_path(format!("{}/{}-{}", BACKUP_DIR, stamp, attempt));
+    }
+
+    let lock_path = get_path(LOCK_PATH);
+    for dir in ["Immutable", "mutable"] {
+        copy_dir(&get_path(dir), &backup.join(dir), &lock_path)?;
+    }
+
+    Ok(backup)
+}
+
+/// Copies the files directly inside `from` into `to`, leaving out `skip`.
+fn copy_dir(from: &Path, to: &Path, skip: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let path = entry?.path();
+        if path.is_file() && path != skip {
+            fs::copy(&path, to.join(path.file_name().unwrap_or_default()))?;
+        }
+    }
+
+    Ok(())
+}
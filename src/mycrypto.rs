@@ -1,142 +1,371 @@
 use aes::Aes128;
+use aes_gcm::{Aes256Gcm, Nonce};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
 use block_modes::{BlockMode, Ecb};
 use block_modes::block_padding::Pkcs7;
 use hex_literal::hex;
+use rand::RngCore;
 use std::str;
 
 type Aes128Ecb = Ecb<Aes128, Pkcs7>;
 
+use std::collections::HashMap;
+use std::ffi::OsString;
 use std::fs::{self, File};
-use std::io::{Read, Write};
+use std::io::{self, ErrorKind, Read, Write};
 use std::path::{Path, PathBuf};
 
 use crate::commands::get_path;
+use crate::shutdown::hold_writes;
 
-/// Encrypts all `.txt` files within a specified directory using AES-128 in ECB mode.
+/// Marks the start of every encrypted channel file. The first byte can never
+/// start a line of text, so a plaintext file is never mistaken for ciphertext.
+const MAGIC: &[u8; 4] = b"\x89CCV";
+/// Version of the encrypted file layout that follows `MAGIC`.
+const FILE_VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 1 + NONCE_LEN;
+
+/// Returns whether `data` starts with the encrypted file header.
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Returns whether `data` looks like a channel that was left decrypted on disk:
+/// no encrypted file header, and valid text.
+pub fn is_plaintext(data: &[u8]) -> bool {
+    !is_encrypted(data) && str::from_utf8(data).is_ok()
+}
+
+/// Encrypts `plaintext` with AES-256-GCM under a fresh random nonce.
 ///
 /// # Parameters
 ///
-/// * `path_str` - Directory path as a string
-/// * `key` - Encryption key as a hex string
+/// * `key` - 32 byte encryption key
+/// * `plaintext` - Bytes to encrypt
 ///
 /// # Return Value
 ///
-/// An `std::io::Result<()>` that represents the success or failure of the encryption operation.
+/// The encrypted file: `MAGIC`, the format version, the nonce, then the ciphertext and tag.
+/// The magic and version are authenticated along with the ciphertext.
 ///
 /// # Example
 ///
 /// ```rust
-/// use crypto_lib::encrypt_files;
+/// let key = [7u8; 32];
+/// let sealed = encrypt_bytes(&key, b"hello").unwrap();
+/// assert_eq!(decrypt_bytes(&key, &sealed).unwrap(), b"hello");
+/// ```
+pub fn encrypt_bytes(key: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    let cipher = Aes256Gcm::new_from_slice(key)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "encryption key must be 32 bytes"))?;
+
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let mut output = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+    output.extend_from_slice(MAGIC);
+    output.push(FILE_VERSION);
+    output.extend_from_slice(&nonce);
+
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: &output[..MAGIC.len() + 1] })
+        .map_err(|_| io::Error::other("encryption failed"))?;
+    output.extend_from_slice(&ciphertext);
+
+    Ok(output)
+}
+
+/// Decrypts data produced by `encrypt_bytes`.
 ///
-/// let key = "2b7e151628aed2a6abf7158809cf4f3c";
-/// let path_str = "/home/user/sample_dir";
+/// # Parameters
 ///
-/// match encrypt_files(path_str, key) {
-///     Ok(_) => println!("Encryption successful!"),
-///     Err(e) => println!("Encryption failed: {}", e),
-/// }
-/// ```
-pub fn encrypt_files(path_str: &str, key: &str) -> std::io::Result<()> {
+/// * `key` - 32 byte encryption key
+/// * `data` - The encrypted file contents
+///
+/// # Return Value
+///
+/// The plaintext, or an `InvalidData` error if the header is not recognised or the
+/// authentication tag does not match (a wrong key, or a file that was modified).
+pub fn decrypt_bytes(key: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+    if !is_encrypted(data) || data.len() < HEADER_LEN {
+        return Err(io::Error::new(ErrorKind::InvalidData, "not an encrypted channel file"));
+    }
+    if data[MAGIC.len()] != FILE_VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported encrypted file version {}", data[MAGIC.len()]),
+        ));
+    }
+
+    let cipher = Aes256Gcm::new_from_slice(key)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "encryption key must be 32 bytes"))?;
+    let nonce = Nonce::from_slice(&data[MAGIC.len() + 1..HEADER_LEN]);
+
+    cipher
+        .decrypt(nonce, Payload { msg: &data[HEADER_LEN..], aad: &data[..MAGIC.len() + 1] })
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "authentication failed, wrong key or file was modified"))
+}
+
+/// Encrypts like `encrypt_bytes`, but leaves `MAGIC` and the format version out of
+/// the output, so it can't be told apart from random bytes.
+///
+/// # Return Value
+///
+/// The nonce, then the ciphertext and tag. The magic and version are still
+/// authenticated, `decrypt_unmarked` puts them back.
+pub fn encrypt_unmarked(key: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    let mut sealed = encrypt_bytes(key, plaintext)?;
+    sealed.drain(..MAGIC.len() + 1);
+    Ok(sealed)
+}
+
+/// Decrypts data produced by `encrypt_unmarked`.
+///
+/// # Return Value
+///
+/// The plaintext, or an `InvalidData` error if the key is wrong or the data was modified.
+pub fn decrypt_unmarked(key: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut marked = Vec::with_capacity(MAGIC.len() + 1 + data.len());
+    marked.extend_from_slice(MAGIC);
+    marked.push(FILE_VERSION);
+    marked.extend_from_slice(data);
+    decrypt_bytes(key, &marked)
+}
+
+/// Random bytes laid out like an encrypted file holding `len` bytes of plaintext.
+///
+/// Nothing can decrypt them, they only make real files harder to count.
+pub fn chaff_bytes(len: usize) -> Vec<u8> {
+    let mut output = vec![0u8; HEADER_LEN + len + 16];
+    rand::thread_rng().fill_bytes(&mut output);
+    output[..MAGIC.len()].copy_from_slice(MAGIC);
+    output[MAGIC.len()] = FILE_VERSION;
+    output
+}
+
+/// Decrypts a file written by the old AES-128-ECB scheme, used before files had a header.
+fn decrypt_legacy(key: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
     let iv = hex!("");
 
-    let key_bytes = hex::decode(key).expect("Failed to decode key");
+    let cipher = Aes128Ecb::new_from_slices(key, &iv)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "legacy key must be 16 bytes"))?;
 
+    let mut buffer = data.to_vec();
+    let plaintext = cipher.decrypt(&mut buffer)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "legacy decryption failed, wrong key or file was modified"))?;
+
+    Ok(plaintext.to_vec())
+}
+
+/// Lists the `.txt` files directly inside a directory.
+fn txt_files(path_str: &str) -> io::Result<Vec<PathBuf>> {
     let path_buf = PathBuf::from(path_str);
     let file_path = get_path(path_buf);
 
-    // Get all .txt files within the specified directory
     let path = Path::new(&file_path);
-    let txt_files: Vec<PathBuf> = fs::read_dir(path)?
-        .filter_map(|entry| {
-            let path = entry.unwrap().path();
-            if path.is_file() && path.extension().unwrap() == "txt" {
-                Some(path)
-            } else {
-                None
-            }
-        })
-        .collect();
+    let mut txt_files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "txt") {
+            txt_files.push(path);
+        }
+    }
 
-    // Encrypt each file
-    for file in txt_files {
-        let mut input_file = File::open(&file)?;
-        let mut input_data = Vec::new();
-        input_file.read_to_end(&mut input_data)?;
+    Ok(txt_files)
+}
+
+/// Reads and decrypts a channel file into memory. The plaintext never touches the disk.
+///
+/// # Parameters
+///
+/// * `path` - Path of the encrypted file
+/// * `key` - 32 byte data key
+///
+/// # Return Value
+///
+/// The decrypted text, or an error if the file can't be read, fails authentication
+/// or isn't valid UTF-8.
+///
+/// # Example
+///
+/// ```rust
+/// let contents = read_encrypted_file(get_path("mutable/main.txt"), &vault.key)?;
+/// println!("{}", contents);
+/// ```
+pub fn read_encrypted_file(path: impl AsRef<Path>, key: &[u8]) -> io::Result<String> {
+    let data = fs::read(path)?;
+    let plaintext = decrypt_bytes(key, &data)?;
 
-        let cipher = Aes128Ecb::new_from_slices(&key_bytes, &iv).unwrap();
+    String::from_utf8(plaintext)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "decrypted file is not valid text"))
+}
 
-        let mut buffer = vec![0u8; input_data.len() * 2]; // use a buffer with a fixed size
-        buffer[..input_data.len()].copy_from_slice(&input_data);
+/// Encrypts `contents` and writes it to a channel file.
+///
+/// The ciphertext is written to a temporary file next to the target and renamed
+/// over it, so an interrupted write leaves either the old or the new version,
+/// never a truncated one.
+///
+/// # Parameters
+///
+/// * `path` - Path of the encrypted file
+/// * `key` - 32 byte data key
+/// * `contents` - The text to store
+///
+/// # Example
+///
+/// ```rust
+/// write_encrypted_file(get_path("mutable/main.txt"), &vault.key, "Title: Main board\n")?;
+/// ```
+pub fn write_encrypted_file(path: impl AsRef<Path>, key: &[u8], contents: &str) -> io::Result<()> {
+    let path = path.as_ref();
+    let ciphertext = encrypt_bytes(key, contents.as_bytes())?;
 
-        let ciphertext = cipher.encrypt(&mut buffer, input_data.len()).unwrap();
+    let _writes = hold_writes();
+    let temp_path = path.with_extension("tmp");
+    let mut temp_file = File::create(&temp_path)?;
+    temp_file.write_all(&ciphertext)?;
+    temp_file.sync_all()?;
 
-        let mut output_file = File::create(file)?;
-        output_file.write_all(&ciphertext)?;
-    }
+    fs::rename(temp_path, path)
+}
 
-    Ok(())
+/// Decrypts the contents of a file written by the old AES-128-ECB scheme.
+///
+/// A file an old version left decrypted after a crash is taken as it is.
+///
+/// # Return Value
+///
+/// The text, and whether the file had been left decrypted.
+fn decrypt_legacy_text(legacy_key: &[u8], data: &[u8]) -> io::Result<(String, bool)> {
+    decrypt_legacy(legacy_key, data)
+        .and_then(|decrypted| String::from_utf8(decrypted)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "decrypted file is not valid text")))
+        .map(|decrypted| (decrypted, false))
+        .or_else(|err| match is_plaintext(data) {
+            true => Ok((String::from_utf8_lossy(data).into_owned(), true)),
+            false => Err(err),
+        })
 }
 
-/// Decrypts all `.txt` files within a specified directory using AES-128 in ECB mode.
+/// Reads a file written by the old AES-128-ECB scheme into memory, without converting it.
+///
+/// # Parameters
+///
+/// * `path` - Path of the legacy file
+/// * `legacy_key` - The old 16 byte key
+///
+/// # Example
+///
+/// ```rust
+/// let contents = read_legacy_file(get_path("mutable/<sha256 of contents>.txt"), &legacy_key())?;
+/// ```
+pub fn read_legacy_file(path: impl AsRef<Path>, legacy_key: &[u8]) -> io::Result<String> {
+    let data = fs::read(path)?;
+
+    decrypt_legacy_text(legacy_key, &data).map(|(text, _)| text)
+}
+
+/// Reports what `convert_legacy_files` would do to every `.txt` file in a directory,
+/// without writing anything.
 ///
 /// # Parameters
 ///
 /// * `path_str` - Directory path as a string
-/// * `key` - Decryption key as a hex string
+/// * `legacy_key` - The old 16 byte key
 ///
 /// # Return Value
 ///
-/// An `std::io::Result<()>` that represents the success or failure of the decryption operation.
+/// One line per file, and how many of the files could not be converted.
 ///
 /// # Example
 ///
 /// ```rust
-/// use crypto_lib::decrypt_files;
-///
-/// let key = "2b7e151628aed2a6abf7158809cf4f3c";
-/// let path_str = "/home/user/sample_dir";
-///
-/// match decrypt_files(path_str, key) {
-///     Ok(_) => println!("Decryption successful!"),
-///     Err(e) => println!("Decryption failed: {}", e),
+/// let (report, failed) = check_legacy_files("mutable", &legacy_key())?;
+/// for line in report {
+///     println!("{}", line);
 /// }
 /// ```
-pub fn decrypt_files(path_str: &str, key: &str) -> std::io::Result<()> {
-    let iv = hex!("");
-
-    let key_bytes = hex::decode(key).expect("Failed to decode key");
-
-    let path_buf = PathBuf::from(path_str);
-    let file_path = get_path(path_buf);
+pub fn check_legacy_files(path_str: &str, legacy_key: &[u8]) -> io::Result<(Vec<String>, usize)> {
+    let mut report = Vec::new();
+    let mut failed = 0;
+    for file in txt_files(path_str)? {
+        let name = file.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let data = fs::read(&file)?;
 
-    // Get all .txt files within the specified directory
-    let path = Path::new(&file_path);
-    let txt_files: Vec<PathBuf> = fs::read_dir(path)?
-        .filter_map(|entry| {
-            let path = entry.unwrap().path();
-            if path.is_file() && path.extension().unwrap() == "txt" {
-                Some(path)
-            } else {
-                None
+        match decrypt_legacy_text(legacy_key, &data) {
+            Ok((_, false)) => report.push(format!("{}: would be converted", name)),
+            Ok((_, true)) => report.push(format!("{}: had been left decrypted, would be encrypted as it is", name)),
+            Err(err) => {
+                report.push(format!("{}: could not be decrypted ({})", name, err));
+                failed += 1;
             }
-        })
-        .collect();
+        }
+    }
+
+    Ok((report, failed))
+}
 
-    // Decrypt each file
-    for file in txt_files {
+/// Converts every `.txt` file in a directory from the old AES-128-ECB scheme to
+/// AES-256-GCM, writing the converted files into another directory.
+///
+/// Each file is decrypted in memory and written with `write_encrypted_file`, so the
+/// plaintext is never written out. Files an old version left decrypted after a crash
+/// are encrypted as they are. The originals are not touched.
+///
+/// # Parameters
+///
+/// * `path_str` - Directory path as a string
+/// * `to` - Directory to write the converted files into
+/// * `legacy_key` - The old 16 byte key
+/// * `key` - 32 byte data key to encrypt with
+/// * `names` - The new name of each file by its old one, files not in it keep their name
+///
+/// # Return Value
+///
+/// An `std::io::Result<()>`. If any file failed to decrypt, an `InvalidData` error saying how many did.
+///
+/// # Example
+///
+/// ```rust
+/// match convert_legacy_files("mutable", &staging, &legacy_key(), &key, &names) {
+///     Ok(_) => println!("Conversion successful!"),
+///     Err(e) => println!("Conversion failed: {}", e),
+/// }
+/// ```
+pub fn convert_legacy_files(path_str: &str, to: &Path, legacy_key: &[u8], key: &[u8], names: &HashMap<OsString, String>) -> std::io::Result<()> {
+    let mut failed = 0;
+    for file in txt_files(path_str)? {
         let mut input_file = File::open(&file)?;
         let mut input_data = Vec::new();
         input_file.read_to_end(&mut input_data)?;
 
-        let cipher = Aes128Ecb::new_from_slices(&key_bytes, &iv).unwrap();
-
-        let mut buffer = input_data.clone();
-
-        let decrypted_ciphertext = cipher.decrypt(&mut buffer).unwrap();
+        let file_name = file.file_name().unwrap_or_default();
+        let converted = match names.get(file_name) {
+            Some(name) => to.join(name),
+            None => to.join(file_name),
+        };
+        match decrypt_legacy_text(legacy_key, &input_data) {
+            Ok((decrypted, left_decrypted)) => {
+                if left_decrypted {
+                    println!("{} had been left decrypted, encrypting it as it is", file.display());
+                }
+                write_encrypted_file(converted, key, &decrypted)?
+            },
+            Err(err) => {
+                println!("error: could not decrypt {}: {}", file.display(), err);
+                failed += 1;
+            }
+        }
+    }
 
-        let mut output_file = File::create(file)?;
-        output_file.write_all(&decrypted_ciphertext)?;
+    if failed > 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{} file(s) could not be converted", failed),
+        ));
     }
 
     Ok(())
-}
\ No newline at end of file
+}
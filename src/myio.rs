@@ -1,4 +1,121 @@
-use std::io;
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use zeroize::Zeroize;
+
+use crate::secret::{SecretBytes, SecretString};
+use crate::shutdown::close_idle;
+
+/// Lines read from stdin by a background thread, so that waiting for input can time out.
+static INPUT: OnceLock<Mutex<Receiver<String>>> = OnceLock::new();
+
+/// How long a question may go unanswered while the vault is unlocked, see `set_prompt_timeout`.
+static PROMPT_TIMEOUT: Mutex<Option<Duration>> = Mutex::new(None);
+
+/// How much of stdin the reader thread takes in at once. Bigger than the buffer std
+/// keeps for stdin, so a read never goes through it.
+const CHUNK_LEN: usize = 16 * 1024;
+
+/// Room a line starts out with, enough for any password without growing.
+const LINE_LEN: usize = 1024;
+
+/// Starts the stdin reader thread the first time input is needed.
+fn input_lines() -> &'static Mutex<Receiver<String>> {
+    INPUT.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || read_lines(sender));
+        Mutex::new(receiver)
+    })
+}
+
+/// Reads stdin line by line and sends each line on, until stdin is closed.
+///
+/// Any line may be a password, so none of it is left behind in the reader's memory:
+/// stdin is read into a buffer that is wiped after every read, a line that outgrows
+/// its buffer is moved to a bigger one and the old one wiped, and the line itself is
+/// handed over, not copied. `io::stdin().lines()` would keep every line typed in the
+/// buffer std shares for stdin, and in the buffers `String` leaves as it grows.
+fn read_lines(sender: Sender<String>) {
+    let mut chunk = SecretBytes::new(vec![0u8; CHUNK_LEN]);
+    let mut line = Vec::with_capacity(LINE_LEN);
+
+    loop {
+        let read = match read_stdin(&mut chunk) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+
+        for &byte in &chunk[..read] {
+            if byte != b'\n' {
+                push_byte(&mut line, byte);
+                continue;
+            }
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            let done = mem::replace(&mut line, Vec::with_capacity(LINE_LEN));
+            if sender.send(line_text(done)).is_err() {
+                chunk.zeroize();
+                return;
+            }
+        }
+        chunk[..read].zeroize();
+    }
+
+    // the last line, if stdin didn't end with a newline
+    if !line.is_empty() {
+        let _ = sender.send(line_text(line));
+    }
+}
+
+/// Adds a byte to a line, moving it to a bigger buffer and wiping the old one when it is full.
+fn push_byte(line: &mut Vec<u8>, byte: u8) {
+    if line.len() == line.capacity() {
+        let mut bigger = Vec::with_capacity(line.capacity() * 2);
+        bigger.extend_from_slice(line);
+        line.zeroize();
+        *line = bigger;
+    }
+    line.push(byte);
+}
+
+/// Turns a line into text without copying it, unless it isn't valid UTF-8.
+fn line_text(line: Vec<u8>) -> String {
+    match String::from_utf8(line) {
+        Ok(text) => text,
+        Err(err) => {
+            let mut line = err.into_bytes();
+            let text = String::from_utf8_lossy(&line).into_owned();
+            line.zeroize();
+            text
+        }
+    }
+}
+
+/// Reads straight from the stdin file descriptor, past the buffer std keeps for stdin.
+#[cfg(unix)]
+fn read_stdin(buffer: &mut [u8]) -> io::Result<usize> {
+    use std::fs::File;
+    use std::mem::ManuallyDrop;
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: stdin stays open for the whole run, and `ManuallyDrop` keeps it from being closed here
+    let mut stdin = ManuallyDrop::new(unsafe { File::from_raw_fd(libc::STDIN_FILENO) });
+    stdin.read(buffer)
+}
+
+/// Reads from stdin. With nothing buffered yet and a read bigger than its buffer, std
+/// hands the read straight through, so the buffer never holds any input.
+#[cfg(not(unix))]
+fn read_stdin(buffer: &mut [u8]) -> io::Result<usize> {
+    io::stdin().lock().read(buffer)
+}
 
 /// This function takes a `&str` as an argument and returns a `String` as output. It prints out the string passed in as an argument, and then reads a single line of user input, trims it, and parses it into a `String` before returning it.
 ///
@@ -7,21 +124,204 @@ use std::io;
 /// ```
 /// let name = myinput("What's your name? ");
 /// ```
-/// 
+///
 /// # Errors
-/// 
-/// This function may panic if there is an error reading or parsing the user input.
+///
+/// Once stdin is closed this returns an empty string for every call.
 pub fn myinput(msg: &str) -> String {
+    myinput_timeout(msg, None).unwrap_or_default()
+}
+
+/// Sets how long `myinput` and `read_password` wait before the app closes itself.
+///
+/// The main prompt locks the vault when it is left idle, see `idle_input`, but a
+/// question asked in the middle of a command (a y/n confirmation, a password, a
+/// keyfile path) can't lock the vault it is borrowed from. So while the vault is
+/// unlocked such a question is given the same timeout, and the app closes, the way
+/// it does on Ctrl-C, if it isn't answered in time. `None` waits forever, which is
+/// what the prompts before the vault is unlocked and the lock screen use.
+///
+/// # Examples
+///
+/// ```
+/// set_prompt_timeout(vault.idle_timeout());
+/// let sure = myinput("Are you sure you want to delete? (y/n)\n"); // closes after the timeout
+/// ```
+pub fn set_prompt_timeout(timeout: Option<Duration>) {
+    *PROMPT_TIMEOUT.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = timeout;
+}
+
+/// Like `myinput`, but gives up if no line arrives within `timeout`.
+///
+/// # Arguments
+///
+/// * `msg` - Printed before waiting.
+/// * `timeout` - How long to wait, `None` waits for as long as it takes.
+///
+/// # Returns
+///
+/// The trimmed line, `RecvTimeoutError::Timeout` if the time ran out first, or
+/// `RecvTimeoutError::Disconnected` if stdin was closed.
+///
+/// # Examples
+///
+/// ```
+/// match myinput_timeout("", Some(Duration::from_secs(300))) {
+///     Ok(line) => println!("got {}", line),
+///     Err(RecvTimeoutError::Timeout) => println!("idle for five minutes"),
+///     Err(RecvTimeoutError::Disconnected) => return,
+/// }
+/// ```
+pub fn myinput_timeout(msg: &str, timeout: Option<Duration>) -> Result<String, RecvTimeoutError> {
+    next_line(msg, timeout).map(|input| input.trim().to_owned())
+}
+
+/// Reads a password the way `myinput` reads a line, but without showing it and
+/// straight into a `SecretString`. The reader thread wipes what it read, see
+/// `read_lines`, so once the password is dropped no copy of it is left behind in the
+/// app's memory. What the terminal and the operating system keep is out of its reach.
+///
+/// Terminal echo is turned off while the password is typed, so it never shows on
+/// screen or in the scrollback. When stdin is not a terminal (input piped in from a
+/// file or another program) there is no echo to turn off and the line is read as it is.
+///
+/// # Returns
+///
+/// The trimmed password, or `None` once stdin is closed.
+///
+/// # Examples
+///
+/// ```
+/// let Some(password) = read_password("Enter password:\n") else { return };
+/// let keyfile = keyfile_for(&header, &password);
+/// let (slot, key) = header.unlock(&password, keyfile.as_ref())?;
+/// ```
+pub fn read_password(msg: &str) -> Option<SecretString> {
+    let hidden = hide_echo();
+    let input = next_line(msg, None);
+    if hidden {
+        restore_echo();
+        // the enter key wasn't echoed either
+        println!();
+    }
+    let mut input = input.ok()?;
+
+    // trimmed in place, a trimmed copy would leave the original behind
+    input.truncate(input.trim_end().len());
+    let leading = input.len() - input.trim_start().len();
+    input.drain(..leading);
+
+    Some(SecretString::new(input))
+}
+
+/// Terminal settings from before echo was turned off, put back by `restore_echo`.
+#[cfg(unix)]
+static SAVED_TERMIOS: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+/// Turns off echo on the terminal stdin is reading from.
+///
+/// # Returns
+///
+/// `false` if stdin is not a terminal, or its settings can't be changed.
+#[cfg(unix)]
+fn hide_echo() -> bool {
+    // SAFETY: `termios` is plain data, filled in by `tcgetattr` before it is used
+    unsafe {
+        if libc::isatty(libc::STDIN_FILENO) == 0 {
+            return false;
+        }
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+            return false;
+        }
+
+        let mut hidden = termios;
+        hidden.c_lflag &= !libc::ECHO;
+        if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &hidden) != 0 {
+            return false;
+        }
+        *SAVED_TERMIOS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(termios);
+    }
+    true
+}
+
+/// Turns terminal echo back on if `hide_echo` turned it off. Also called when the
+/// app is closed by a signal or a panic, so the terminal is never left without echo.
+#[cfg(unix)]
+pub fn restore_echo() {
+    let saved = SAVED_TERMIOS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+    if let Some(termios) = saved {
+        // SAFETY: restores settings `tcgetattr` returned for the same terminal
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) };
+    }
+}
+
+/// Console mode from before echo was turned off, put back by `restore_echo`.
+#[cfg(windows)]
+static SAVED_CONSOLE_MODE: Mutex<Option<u32>> = Mutex::new(None);
+
+/// Turns off echo on the console stdin is reading from.
+///
+/// # Returns
+///
+/// `false` if stdin is not a console, or its mode can't be changed.
+#[cfg(windows)]
+fn hide_echo() -> bool {
+    use windows_sys::Win32::System::Console::{GetConsoleMode, GetStdHandle, SetConsoleMode, ENABLE_ECHO_INPUT, STD_INPUT_HANDLE};
+
+    // SAFETY: only queries and sets the mode of this process's own stdin handle
+    unsafe {
+        let handle = GetStdHandle(STD_INPUT_HANDLE);
+        let mut mode = 0;
+        if GetConsoleMode(handle, &mut mode) == 0 {
+            return false;
+        }
+        if SetConsoleMode(handle, mode & !ENABLE_ECHO_INPUT) == 0 {
+            return false;
+        }
+        *SAVED_CONSOLE_MODE.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(mode);
+    }
+    true
+}
+
+/// Turns console echo back on if `hide_echo` turned it off. Also called when the
+/// app is closed by a signal or a panic, so the console is never left without echo.
+#[cfg(windows)]
+pub fn restore_echo() {
+    use windows_sys::Win32::System::Console::{GetStdHandle, SetConsoleMode, STD_INPUT_HANDLE};
+
+    let saved = SAVED_CONSOLE_MODE.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+    if let Some(mode) = saved {
+        // SAFETY: restores the mode `GetConsoleMode` returned for the same handle
+        unsafe { SetConsoleMode(GetStdHandle(STD_INPUT_HANDLE), mode) };
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn hide_echo() -> bool {
+    false
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn restore_echo() {}
+
+/// Prints `msg` and waits for the next line from the stdin reader thread.
+///
+/// Without a `timeout` of its own, a question that isn't answered within the prompt
+/// timeout closes the app, see `set_prompt_timeout`.
+fn next_line(msg: &str, timeout: Option<Duration>) -> Result<String, RecvTimeoutError> {
     print!("{}", msg);
-    let mut input = String::with_capacity(5);            // get input
-    io::stdin()
-        .read_line(&mut input)
-        .expect("Error reading input");
-
-    let input:String = input
-        .trim()
-        .parse()
-        .expect("Error parsing number");
-
-    return input;
-}
\ No newline at end of file
+    io::stdout().flush().expect("Error writing output");
+
+    let lines = input_lines().lock().expect("Input reader poisoned");
+    match timeout {
+        Some(timeout) => lines.recv_timeout(timeout),
+        None => match *PROMPT_TIMEOUT.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+            Some(timeout) => match lines.recv_timeout(timeout) {
+                Err(RecvTimeoutError::Timeout) => close_idle(),
+                line => line,
+            },
+            None => lines.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        },
+    }
+}
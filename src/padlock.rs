@@ -0,0 +1,128 @@
+use std::io::{self, ErrorKind};
+
+use crate::mycrypto::{decrypt_bytes, encrypt_bytes};
+use crate::password::{derive_key, KdfParams};
+use crate::secret::SecretBytes;
+use crate::shred::{read_stored, write_stored};
+use crate::vault::{Vault, VaultHeader};
+
+/// First line of a locked channel. Every other channel starts with `Title: `.
+const LOCK_MARKER: &str = "Locked channel\n";
+/// Prefix of the key derivation fields in a locked channel, as `KdfParams` names them.
+const LOCK_SLOT: &str = "lock";
+/// Field holding the channel, encrypted under the channel key.
+const SEALED_FIELD: &str = "lock_sealed";
+
+/// Returns whether a decrypted channel file holds a locked channel.
+///
+/// A locked channel is still encrypted under the data key and its own key like
+/// every other one (see `shred.rs`), but inside that is another layer: `Locked
+/// channel` and then `key=value` lines with the salt and costs of a key derived
+/// from the channel's own password, and the channel encrypted under that key.
+/// Unlocking the vault alone doesn't open it, and the data key never encrypts it
+/// directly, so a re-key leaves the inner layer as it is.
+pub fn is_locked(stored: &str) -> bool {
+    stored.starts_with(LOCK_MARKER)
+}
+
+/// Puts a channel under its own password.
+///
+/// # Arguments
+///
+/// * `document` - The channel as it is stored now, its title and messages.
+/// * `password` - The channel's password.
+///
+/// # Returns
+///
+/// The locked channel, to be written with `write_stored` like any other.
+///
+/// # Examples
+///
+/// ```
+/// let locked = lock_document("Title: diary\n", "channel password")?;
+/// assert!(is_locked(&locked));
+/// ```
+pub fn lock_document(document: &str, password: &str) -> io::Result<String> {
+    let params = KdfParams::generate();
+    let key = derive_key(password, &params)?;
+
+    let mut fields = VaultHeader::new();
+    params.write_to(&mut fields, LOCK_SLOT);
+    seal_document(fields, &key, document)
+}
+
+/// Derives a locked channel's key from its password.
+///
+/// # Errors
+///
+/// Returns an `InvalidData` error if the password is wrong.
+pub fn unlock_document(stored: &str, password: &str) -> io::Result<SecretBytes> {
+    let fields = lock_fields(stored)?;
+    let key = derive_key(password, &KdfParams::from_header(&fields, LOCK_SLOT)?)?;
+    open_sealed(&fields, &key).map_err(|_| io::Error::new(ErrorKind::InvalidData, "wrong password"))?;
+    Ok(key)
+}
+
+/// Decrypts a locked channel with the key from `unlock_document`.
+pub fn open_document(stored: &str, key: &[u8]) -> io::Result<String> {
+    open_sealed(&lock_fields(stored)?, key)
+}
+
+/// Reads the fields that follow `LOCK_MARKER`.
+fn lock_fields(stored: &str) -> io::Result<VaultHeader> {
+    let fields = stored.strip_prefix(LOCK_MARKER).ok_or_else(|| {
+        io::Error::new(ErrorKind::InvalidData, "the channel is not locked")
+    })?;
+    VaultHeader::parse(fields)
+}
+
+fn open_sealed(fields: &VaultHeader, key: &[u8]) -> io::Result<String> {
+    let plaintext = decrypt_bytes(key, &fields.require_hex(SEALED_FIELD)?)?;
+    String::from_utf8(plaintext)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "decrypted file is not valid text"))
+}
+
+/// Encrypts `document` under `key` into the lock fields, keeping the salt and costs.
+fn seal_document(mut fields: VaultHeader, key: &[u8], document: &str) -> io::Result<String> {
+    fields.set(SEALED_FIELD, hex::encode(encrypt_bytes(key, document.as_bytes())?));
+    Ok(format!("{}{}", LOCK_MARKER, fields.to_text()))
+}
+
+/// Reads a channel, going through the open locked channel's key if it is locked.
+///
+/// # Errors
+///
+/// Returns a `PermissionDenied` error if the channel is locked and its key isn't in
+/// `vault.channel_key`, for example because the vault locked itself while it was open.
+///
+/// # Examples
+///
+/// ```
+/// let document = read_channel(vault, "diary")?;
+/// ```
+pub fn read_channel(vault: &Vault, name: &str) -> io::Result<String> {
+    let stored = read_stored(vault, name)?;
+    if !is_locked(&stored) {
+        return Ok(stored);
+    }
+
+    let key = vault.channel_key.as_ref().ok_or_else(|| {
+        io::Error::new(ErrorKind::PermissionDenied, "the channel is locked, select it again to enter its password")
+    })?;
+    open_document(&stored, key)
+}
+
+/// Writes a channel read with `read_channel`, locking it again under the same key
+/// if it is locked.
+pub fn write_channel(vault: &mut Vault, name: &str, document: &str) -> io::Result<()> {
+    let stored = read_stored(vault, name)?;
+    if !is_locked(&stored) {
+        return write_stored(vault, name, document);
+    }
+
+    let key = vault.channel_key.as_ref().ok_or_else(|| {
+        io::Error::new(ErrorKind::PermissionDenied, "the channel is locked, select it again to enter its password")
+    })?;
+    let sealed = seal_document(lock_fields(&stored)?, key, document)?;
+    write_stored(vault, name, &sealed)
+}
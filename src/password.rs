@@ -1,9 +1,17 @@
-use std::fs::File;
 use std::fs;
-use std::io::Write;
+use std::io::{self, ErrorKind};
 use sha2::{Sha256, Digest};
-use rand::Rng;
+use rand::RngCore;
+use std::path::PathBuf;
+use argon2::{Algorithm, Argon2, Params, Version};
+use argon2::password_hash::rand_core::OsRng;
+use bip39::Mnemonic;
+use subtle::ConstantTimeEq;
 use crate::commands::get_path;
+use crate::recovery::normalize_phrase;
+use crate::secret::{SecretBytes, SecretString};
+use crate::shred::shred_file;
+use crate::vault::VaultHeader;
 
 pub fn get_hash(input_str: &str) -> String {
     // Create a Sha256 object
@@ -21,101 +29,205 @@ pub fn get_hash(input_str: &str) -> String {
 
 
 
-/// Updates the SHA256 password file with the provided `password`.
-/// 
-/// # Arguments
-/// 
-/// * `password` - The password string to be hashed and written to the file.
-/// * `is_master` - Boolean value indicating whether the file is a master password file.
-/// 
-/// # Returns
-/// 
-/// A `Result<(), std::io::Error>` which will be `Ok(())` on success or `Err(e)` on failure, where `e` is an [`std::io::Error`](https://doc.rust-lang.org/std/io/struct.Error.html).
-/// 
-/// # Examples
-/// 
-/// ```
-/// use std::io;
-/// use crypto_hash::Sha256;
-/// 
-/// let result = update_password_file("mypassword", true);
-/// assert!(result.is_ok());
-/// ```
-pub fn update_password_file(password: &str, is_master: bool) -> Result<(), std::io::Error> {
-//  let mut hasher = Sha256::new();
-//  hasher.update(password.as_bytes());
-//  let hashed_bytes = hasher.finalize();
-//  println!("The type of my_var is: {}", std::any::type_name::<typeof(hashed_bytes)>());
-    let mut file = {
-        if is_master {
-            File::create(get_path("Immutable/SHAmasterpassword.txt"))?
-        } else {
-            File::create(get_path("Immutable/SHApassword.txt"))?
+/// Returns the path of the login or master password file.
+///
+/// Format 0 vaults kept a hash of each password there. The key slots sealed in
+/// `vault.bin` are all that unlocks now, and a hash would let anyone check a
+/// password without them, so the files are only read to migrate such a vault, then
+/// emptied.
+fn password_file_path(is_master: bool) -> PathBuf {
+    if is_master {
+        get_path("Immutable/SHAmasterpassword.txt")
+    } else {
+        get_path("Immutable/SHApassword.txt")
+    }
+}
+
+/// Shreds both password files and leaves them empty, as if the vault had never been set up.
+pub fn clear_password_files() -> io::Result<()> {
+    for is_master in [false, true] {
+        let path = password_file_path(is_master);
+        if path.exists() {
+            shred_file(&path)?;
         }
-    };
-//  file.write_all(format!("{:x}", hashed_bytes).as_bytes())?;
-    file.write_all(get_hash(password).as_bytes())?;
+        fs::write(path, "")?;
+    }
     Ok(())
 }
 
-/// Checks whether the given password matches the stored passwords.
+/// Whether both password files are missing or empty, as they are once a format 0
+/// vault has been migrated, see `clear_password_files`.
+pub fn password_files_cleared() -> bool {
+    [false, true].into_iter().all(|is_master| {
+        fs::read_to_string(password_file_path(is_master)).map_or(true, |stored| stored.trim().is_empty())
+    })
+}
+
+/// Checks whether the given password matches one of the password files of a format 0
+/// vault, without changing anything on disk.
+///
+/// The files hold an unsalted SHA256 hash, compared in constant time.
 ///
 /// # Arguments
 ///
 /// * `password` - The password to check.
+/// * `is_master` - Whether to check against the master password file instead of the password file.
 ///
 /// # Returns
 ///
-/// `true` if the password matches the stored passwords, `false` otherwise.
+/// `true` if the password matches the chosen stored password, `false` otherwise,
+/// including when the password file is missing or empty.
 ///
 /// # Examples
 ///
 /// ```
-/// assert!(check_password("some_password"));
+/// if !password_matches(&password, false) {
+///     println!("wrong password");
+/// }
 /// ```
-pub fn check_password(password: &str) -> bool {
-    let mut hasher = Sha256::new();
-    hasher.update(password.as_bytes());
-    let hashed_bytes = hasher.finalize();
+pub fn password_matches(password: &str, is_master: bool) -> bool {
+    let Ok(stored) = fs::read_to_string(password_file_path(is_master)) else { return false };
+    let stored = stored.trim();
+    if stored.is_empty() {
+        return false;
+    }
+
+    // a recovery phrase typed with capitals or extra spaces still matches
+    let normalized = if is_master { normalize_phrase(password) } else { None };
+    let password = normalized.as_deref().unwrap_or(password);
+
+    let hash = SecretString::new(get_hash(password));
+    stored.as_bytes().ct_eq(hash.as_bytes()).into()
+}
 
-    let sha_password = fs::read_to_string(get_path("Immutable/SHApassword.txt"))
-        .expect("Error reading file");
+/// Default Argon2id memory cost in KiB (64 MiB).
+pub const DEFAULT_M_COST: u32 = 64 * 1024;
+/// Default Argon2id number of passes.
+pub const DEFAULT_T_COST: u32 = 3;
+/// Default Argon2id degree of parallelism.
+pub const DEFAULT_P_COST: u32 = 1;
 
-    let sha_masterpassword = fs::read_to_string(get_path("Immutable/SHAmasterpassword.txt"))
-        .expect("Error reading file");
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
 
-    sha_password.as_bytes() == format!("{:x}", hashed_bytes).as_bytes() || sha_masterpassword.as_bytes() == format!("{:x}", hashed_bytes).as_bytes()
+/// The Argon2id parameters used to turn a password into a key-encryption key.
+///
+/// Every key slot in the vault header gets its own random salt, and the costs are
+/// stored next to it, so they can be raised for new slots without locking anyone
+/// out of an older one.
+pub struct KdfParams {
+    pub salt: Vec<u8>,
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
 }
 
-/// Generates an SHA256 cryptographic key from the provided password, concatenated with a salt value.
+impl KdfParams {
+    /// Creates parameters for a new key slot: a random salt and the default costs.
+    pub fn generate() -> KdfParams {
+        let mut salt = vec![0u8; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+
+        KdfParams {
+            salt,
+            m_cost: DEFAULT_M_COST,
+            t_cost: DEFAULT_T_COST,
+            p_cost: DEFAULT_P_COST,
+        }
+    }
+
+    /// Reads the parameters of one key slot from a vault header.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - The vault header.
+    /// * `slot` - The name the slot's fields are prefixed with, e.g. `user`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidData` error if a field is missing or unparsable, or if the
+    /// header names a KDF other than Argon2id.
+    pub fn from_header(header: &VaultHeader, slot: &str) -> io::Result<KdfParams> {
+        if header.require(&format!("{}_kdf", slot))? != "argon2id" {
+            return Err(io::Error::new(ErrorKind::InvalidData, "vault header names an unknown kdf"));
+        }
+
+        Ok(KdfParams {
+            salt: header.require_hex(&format!("{}_salt", slot))?,
+            m_cost: header.require_number(&format!("{}_m_cost", slot))?,
+            t_cost: header.require_number(&format!("{}_t_cost", slot))?,
+            p_cost: header.require_number(&format!("{}_p_cost", slot))?,
+        })
+    }
+
+    /// Stores the parameters of one key slot in a vault header.
+    pub fn write_to(&self, header: &mut VaultHeader, slot: &str) {
+        header.set(&format!("{}_kdf", slot), "argon2id");
+        header.set(&format!("{}_salt", slot), hex::encode(&self.salt));
+        header.set(&format!("{}_m_cost", slot), self.m_cost);
+        header.set(&format!("{}_t_cost", slot), self.t_cost);
+        header.set(&format!("{}_p_cost", slot), self.p_cost);
+    }
+}
+
+/// Derives a key-encryption key from a password with Argon2id.
+///
+/// The result never encrypts channel files itself, it only wraps the vault's data key.
 ///
 /// # Arguments
 ///
-/// * `password` - A string slice containing the password to generate the key from
+/// * `password` - The password the user typed.
+/// * `params` - The salt and costs stored in the key slot.
 ///
 /// # Examples
 ///
 /// ```
-/// let key = new_key("my_password");
+/// let params = KdfParams::generate();
+/// let key = derive_key("my_password", &params).unwrap();
+/// assert_eq!(key, derive_key("my_password", &params).unwrap());
 /// ```
 ///
 /// # Returns
 ///
-/// A `String` containing the generated SHA256 key.
-pub fn new_key(password: &str) -> String {
-    let salted_password = format!("{}{}", password, "my salt");
-     
+/// The 32 byte key.
+///
+/// # Errors
+///
+/// Returns an `InvalidInput` error if the header's costs or salt are out of Argon2's range.
+pub fn derive_key(password: &str, params: &KdfParams) -> io::Result<SecretBytes> {
+    let argon_params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(KEY_LEN))
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("invalid kdf parameters: {}", e)))?;
+    let argon = Argon2::new(Algorithm::Argon2id, Version::V0x13, argon_params);
+
+    let mut key = SecretBytes::new(vec![0u8; KEY_LEN]);
+    argon.hash_password_into(password.as_bytes(), &params.salt, &mut key)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("key derivation failed: {}", e)))?;
+
+    Ok(key)
+}
+
+/// Returns the 16 byte key every vault was encrypted with before keys were derived
+/// from the password. Only used to read a vault that has no header yet.
+///
+/// The old scheme used the first half of the hex encoded hash as a hex key, which
+/// is the first 16 bytes of the hash.
+pub fn legacy_key() -> SecretBytes {
+    let salted_password = format!("{}{}", "my password", "my salt");
+
     let mut hasher = Sha256::new();
     hasher.update(salted_password.as_bytes());
-    let two_big:String = format!("{:x}", hasher.finalize());
-
-    let len = two_big.len();
-    let key:String = two_big[..len/2].to_owned();
+    let hash = hasher.finalize();
 
-    key
+    SecretBytes::new(hash[..hash.len() / 2].to_vec())
 }
 
-/// Generates a new, random master password and stores it in the password file.
+/// Generates a new, random master password, as a 24 word recovery phrase.
+///
+/// The phrase encodes 256 random bits with the BIP39 English word list, the last
+/// word carrying a checksum, so a mistyped or swapped word is caught instead of
+/// just failing to unlock. Words are separated by single spaces.
+///
+/// Nothing is written, the caller wraps the data key under it.
 ///
 /// # Arguments
 ///
@@ -123,30 +235,27 @@ pub fn new_key(password: &str) -> String {
 ///
 /// # Returns
 ///
-/// * `String` - The master password that was generated.
+/// * `SecretString` - The master password that was generated.
 ///
 /// # Examples
 ///
 /// ```
 /// let master_password = generate_master_password();
-/// println!("Generated master password: {}", master_password);
+/// show_master_password(&master_password);
 /// ```
-pub fn generate_master_password() -> String {
-    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
-                            abcdefghijklmnopqrstuvwxyz\
-                            0123456789";
-    const PASSWORD_LEN: usize = 32;
-
-    let mut rng = rand::thread_rng();
-    let password: String = (0..PASSWORD_LEN)
-        .map(|_| {
-            let idx = rng.gen_range(0..CHARSET.len());
-            CHARSET[idx] as char
-        })
-        .collect();
+pub fn generate_master_password() -> SecretString {
+    let mut entropy = SecretBytes::new(vec![0u8; 32]);
+    OsRng.fill_bytes(&mut entropy);
+    let mnemonic = Mnemonic::from_entropy(&entropy).expect("32 bytes is a valid entropy length");
 
-    update_password_file(password.as_str(), true)
-        .unwrap_or_else(|e| eprintln!("Error updating password: {}", e));
+    // allocated once, so the phrase is never copied into a reallocation
+    let mut password = String::with_capacity(24 * 9);
+    for (i, word) in mnemonic.words().enumerate() {
+        if i > 0 {
+            password.push(' ');
+        }
+        password.push_str(word);
+    }
 
-    password
-}
\ No newline at end of file
+    SecretString::new(password)
+}
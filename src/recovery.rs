@@ -0,0 +1,446 @@
+use std::fs;
+use std::io;
+
+use bip39::{Error, Language, Mnemonic};
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+use rand::seq::index::sample;
+use sharks::{Share, Sharks};
+
+use crate::autolock::clear_screen;
+use crate::commands::get_path;
+use crate::myio::{myinput, myinput_timeout, read_password};
+use crate::secret::{SecretBytes, SecretString};
+
+/// Where the printable recovery sheet is saved, relative to the project directory.
+pub const SHEET_PATH: &str = "recovery-sheet.txt";
+
+/// How many words the user has to type back before the phrase counts as written down.
+const WORDS_TO_CHECK: usize = 3;
+
+/// The first word of every share, which is how a share is told apart from a password.
+const SHARE_PREFIX: &str = "share";
+
+/// Puts a master password typed by the user in the form it was generated in.
+///
+/// A recovery phrase still unlocks when typed in capitals or with extra spaces
+/// between the words. Anything that isn't a valid phrase, such as a master password
+/// from before recovery phrases, is left as it is.
+///
+/// # Returns
+///
+/// The phrase in lowercase with single spaces, or `None` if `password` is not a
+/// recovery phrase.
+///
+/// # Examples
+///
+/// ```
+/// let normalized = normalize_phrase(password);
+/// let password = normalized.as_deref().unwrap_or(password);
+/// ```
+pub fn normalize_phrase(password: &str) -> Option<SecretString> {
+    let mut phrase = String::with_capacity(password.len());
+    for word in password.split_whitespace() {
+        if !phrase.is_empty() {
+            phrase.push(' ');
+        }
+        phrase.extend(word.chars().flat_map(|c| c.to_lowercase()));
+    }
+    let phrase = SecretString::new(phrase);
+
+    Mnemonic::parse_in_normalized(Language::English, &phrase).ok()?;
+    Some(phrase)
+}
+
+/// Explains why a wrong password that looks like a recovery phrase didn't work.
+///
+/// # Returns
+///
+/// A hint for the user if `password` is made of recovery words but one of them is
+/// unknown or the checksum doesn't match, `None` otherwise.
+///
+/// # Examples
+///
+/// ```
+/// if let Some(hint) = phrase_hint(&password) {
+///     println!("{}", hint);
+/// }
+/// ```
+pub fn phrase_hint(password: &str) -> Option<String> {
+    let words: Vec<String> = password.split_whitespace().map(|word| word.to_lowercase()).collect();
+    if words.len() < 12 {
+        return None;
+    }
+
+    match Mnemonic::parse_in_normalized(Language::English, &words.join(" ")) {
+        Err(Error::UnknownWord(i)) => Some(format!("Word {} of the recovery phrase is not a recovery word, check its spelling.", i + 1)),
+        Err(Error::InvalidChecksum) => Some("The recovery phrase has a mistyped or swapped word, its checksum doesn't match.".to_owned()),
+        Err(Error::BadWordCount(count)) => Some(format!("The recovery phrase has {} words, it should have 24.", count)),
+        _ => None,
+    }
+}
+
+/// Shows a newly generated master password and makes sure it was written down.
+///
+/// The words are printed numbered, optionally also as a QR code and saved as a
+/// printable recovery sheet. The screen is then cleared and the user has to type a
+/// few of the words back, seeing the phrase again until they get them right.
+///
+/// Master passwords that aren't recovery phrases are just printed.
+///
+/// # Arguments
+///
+/// * `master_password` - The master password that was generated.
+///
+/// # Examples
+///
+/// ```
+/// let (vault, master_password) = Vault::create(&password, keyfile.as_ref(), random_index())?;
+/// show_master_password(&master_password);
+/// ```
+pub fn show_master_password(master_password: &SecretString) {
+    let words: Vec<&str> = master_password.split(' ').collect();
+    if Mnemonic::parse_in_normalized(Language::English, master_password).is_err() {
+        println!("Here is your Master password {}", &**master_password);
+        return;
+    }
+
+    if myinput("Split the Master password into shares for several people instead of showing it? (y/n)\n").to_lowercase() == "y" {
+        hand_out_shares(master_password);
+        return;
+    }
+
+    println!("\nYour Master password is this recovery phrase. It opens the vault if you forget your password,");
+    println!("write the words down in order and keep them somewhere safe:\n");
+    print_words(&words);
+
+    if myinput("Show it as a QR code too? (y/n)\n").to_lowercase() == "y" {
+        print_qr_code(master_password);
+    }
+    if myinput(&format!("Save a printable recovery sheet to {}? (y/n)\n", SHEET_PATH)).to_lowercase() == "y" {
+        match save_sheet(&words) {
+            Ok(()) => println!("Saved {}. Print it and delete the file, anyone who reads it can open the vault.\n", SHEET_PATH),
+            Err(err) => println!("error: could not save the recovery sheet: {}\n", err),
+        }
+    }
+
+    loop {
+        myinput("Press enter once you have written the words down, the screen will be cleared.\n");
+        clear_screen();
+
+        if check_words(&words) {
+            println!("The recovery phrase is written down correctly.\n");
+            return;
+        }
+        println!("That doesn't match, here is the recovery phrase again:\n");
+        print_words(&words);
+    }
+}
+
+/// Asks for a few random words of the phrase.
+///
+/// # Returns
+///
+/// `true` if every word was typed correctly. Once stdin is closed nobody can
+/// answer, which also counts as `true` so the caller doesn't loop forever.
+fn check_words(words: &[&str]) -> bool {
+    let mut positions = sample(&mut rand::thread_rng(), words.len(), WORDS_TO_CHECK).into_vec();
+    positions.sort_unstable();
+
+    for position in positions {
+        let Ok(answer) = myinput_timeout(&format!("Enter word #{} of the recovery phrase:\n", position + 1), None) else {
+            return true;
+        };
+        if answer.to_lowercase() != words[position] {
+            return false;
+        }
+    }
+    true
+}
+
+/// Prints the words numbered, in four columns.
+fn print_words(words: &[&str]) {
+    let rows = words.len().div_ceil(4);
+    for row in 0..rows {
+        let line: Vec<String> = (row..words.len())
+            .step_by(rows)
+            .map(|i| format!("{:>2}. {:<10}", i + 1, words[i]))
+            .collect();
+        println!("    {}", line.join("  ").trim_end());
+    }
+    println!();
+}
+
+/// Prints text as a QR code made of half-block characters.
+pub fn print_qr_code(text: &str) {
+    match QrCode::new(text.as_bytes()) {
+        // drawn inverted, so it scans on the usual dark terminal background
+        Ok(code) => println!("{}\n", code.render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .build()),
+        Err(err) => println!("error: could not make a QR code: {}\n", err),
+    }
+}
+
+/// Writes the recovery sheet, the numbered words with instructions for using them.
+fn save_sheet(words: &[&str]) -> io::Result<()> {
+    // allocated once, so the words are never copied into a reallocation
+    let mut sheet = String::with_capacity(4096);
+    sheet.push_str("Cognitive Canvas recovery sheet\n");
+    sheet.push_str(&format!("Created {}\n\n", chrono::Local::now().format("%Y-%m-%d %H:%M")));
+    sheet.push_str("These 24 words are the Master password of your vault. Type them in order,\n");
+    sheet.push_str("separated by spaces, when asked for your password to open the vault and\n");
+    sheet.push_str("choose a new password with /newpassword.\n\n");
+    for (i, word) in words.iter().enumerate() {
+        sheet.push_str(&format!("    {:>2}. {}\n", i + 1, word));
+    }
+    sheet.push_str("\nAnyone holding this sheet can open the vault. Keep it somewhere safe,\n");
+    sheet.push_str("and destroy it once a new Master password has been generated.\n");
+
+    let result = fs::write(get_path(SHEET_PATH), &sheet);
+    drop(SecretString::new(sheet));
+    result
+}
+
+/// Splits a recovery phrase into `count` shares, any `threshold` of which rebuild it.
+///
+/// Uses Shamir's secret sharing over the phrase's 256 bits: fewer than `threshold`
+/// shares reveal nothing about it. Each share is written like the phrase itself,
+/// `share <threshold>-<number>-<split>` followed by 24 words whose last word is a
+/// checksum, so typos in a share are caught the same way.
+///
+/// Every split uses new random numbers, shares from two different splits can't be
+/// combined with each other. `<split>` is a random id the shares of one split have
+/// in common, so mixing them up is caught before they are combined into a wrong
+/// phrase.
+///
+/// # Arguments
+///
+/// * `phrase` - The recovery phrase to split.
+/// * `threshold` - How many shares are needed to unlock, at least 2.
+/// * `count` - How many shares to make, at least `threshold` and at most 255.
+///
+/// # Returns
+///
+/// The shares, or `None` if `phrase` is not a recovery phrase.
+///
+/// # Examples
+///
+/// ```
+/// let shares = split_phrase(&master_password, 2, 3).unwrap();
+/// assert_eq!(&*combine_shares(&shares[1..]).unwrap(), &*master_password);
+/// ```
+pub fn split_phrase(phrase: &str, threshold: u8, count: u8) -> Option<Vec<SecretString>> {
+    let mnemonic = Mnemonic::parse_in_normalized(Language::English, phrase).ok()?;
+    let entropy = SecretBytes::new(mnemonic.to_entropy());
+    let split = rand::random::<u16>();
+
+    let shares = Sharks(threshold)
+        .dealer(&entropy)
+        .take(count as usize)
+        .map(|share| {
+            let bytes = SecretBytes::new(Vec::from(&share));
+            let words = Mnemonic::from_entropy(&bytes[1..]).expect("share has the length of the phrase's entropy");
+            join_words(&format!("{} {}-{}-{:04x}", SHARE_PREFIX, threshold, bytes[0], split), words.words())
+        })
+        .collect();
+
+    Some(shares)
+}
+
+/// Rebuilds a recovery phrase from shares made by `split_phrase`.
+///
+/// # Errors
+///
+/// Returns a message for the user if a share can't be read, the shares come from
+/// different splits, or there are fewer than the threshold.
+pub fn combine_shares(shares: &[SecretString]) -> Result<SecretString, String> {
+    let mut first = None;
+    let mut parsed = Vec::with_capacity(shares.len());
+    for text in shares {
+        let (share_threshold, split, share) = parse_share(text)?;
+        if first.get_or_insert((share_threshold, split)) != &(share_threshold, split) {
+            return Err("The shares come from different splits, they can't be combined.".to_owned());
+        }
+        parsed.push(share);
+    }
+    let Some((threshold, _)) = first else {
+        return Err("No shares were given.".to_owned());
+    };
+
+    let entropy = Sharks(threshold)
+        .recover(&parsed)
+        .map(SecretBytes::new)
+        .map_err(|_| format!("{} different shares are needed.", threshold))?;
+    let mnemonic = Mnemonic::from_entropy(&entropy).map_err(|_| "The shares don't fit together.".to_owned())?;
+
+    Ok(join_words("", mnemonic.words()))
+}
+
+/// Reads a password like `read_password`, but also accepts the shares of a split
+/// Master password.
+///
+/// If what is typed is a share, the other shares are asked for until there are
+/// enough to rebuild the recovery phrase, which is returned in place of a password.
+/// Anything else is returned as it was typed.
+///
+/// # Returns
+///
+/// The password or rebuilt phrase, or `None` once stdin is closed or the user gives
+/// up entering shares.
+///
+/// # Examples
+///
+/// ```
+/// let Some(password) = read_password_or_shares("Enter password:\n") else { return };
+/// let keyfile = keyfile_for(&header, &password);
+/// let (slot, key) = header.unlock(&password, keyfile.as_ref())?;
+/// ```
+pub fn read_password_or_shares(msg: &str) -> Option<SecretString> {
+    let first = read_password(msg)?;
+    if first.split_whitespace().next() != Some(SHARE_PREFIX) {
+        return Some(first);
+    }
+
+    let mut shares: Vec<SecretString> = Vec::new();
+    let mut next = Some(first);
+    loop {
+        let text = match next.take() {
+            Some(text) => text,
+            None => {
+                let prompt = format!("Enter another share, or leave this empty to give up ({} so far):\n", shares.len());
+                let text = read_password(&prompt)?;
+                if text.is_empty() {
+                    println!("Stopped entering shares.");
+                    return None;
+                }
+                text
+            }
+        };
+
+        let (threshold, split, share) = match parse_share(&text) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                println!("{}", err);
+                continue;
+            }
+        };
+        let mut duplicate = false;
+        let mut other_split = false;
+        for known in &shares {
+            if let Ok((known_threshold, known_split, known)) = parse_share(known) {
+                duplicate |= known.x.0 == share.x.0;
+                other_split |= known_threshold != threshold || known_split != split;
+            }
+        }
+        if duplicate {
+            println!("That share was already entered.");
+            continue;
+        }
+        if other_split {
+            println!("That share is from a different split than the first one.");
+            continue;
+        }
+
+        shares.push(text);
+        if shares.len() >= threshold as usize {
+            return match combine_shares(&shares) {
+                Ok(phrase) => Some(phrase),
+                Err(err) => {
+                    println!("{}", err);
+                    Some(SecretString::new(String::new()))
+                }
+            };
+        }
+        println!("Share accepted, {} more needed.", threshold as usize - shares.len());
+    }
+}
+
+/// Asks how to split a Master password and shows each share on its own screen.
+///
+/// # Arguments
+///
+/// * `master_password` - The recovery phrase to split.
+pub fn hand_out_shares(master_password: &SecretString) {
+    let count = loop {
+        match myinput("How many people should get a share? (2-255)\n").parse::<u8>() {
+            Ok(count) if count >= 2 => break count,
+            _ => println!("Enter a number from 2 to 255."),
+        }
+    };
+    let threshold = loop {
+        match myinput(&format!("How many of them are needed to unlock the vault? (2-{})\n", count)).parse::<u8>() {
+            Ok(threshold) if (2..=count).contains(&threshold) => break threshold,
+            _ => println!("Enter a number from 2 to {}.", count),
+        }
+    };
+
+    let Some(shares) = split_phrase(master_password, threshold, count) else {
+        println!("This Master password can't be split, generate a new one with /newpassword first.\n");
+        return;
+    };
+
+    println!("\nAny {} of the {} shares unlock the vault, fewer reveal nothing about it.", threshold, count);
+    println!("Each share is shown on its own, the screen is cleared before the next one.\n");
+    for (i, share) in shares.iter().enumerate() {
+        myinput(&format!("Press enter to show share {} of {}.\n", i + 1, count));
+        clear_screen();
+        let words: Vec<&str> = share.split(' ').collect();
+        println!("Share {} of {}, {} needed to unlock. Type it starting with `{} {}`:\n", i + 1, count, threshold, words[0], words[1]);
+        print_words(&words[2..]);
+        myinput("Press enter once it has been written down, the screen will be cleared.\n");
+        clear_screen();
+    }
+
+    println!("All {} shares were handed out. To unlock, type a share at the password prompt.\n", count);
+}
+
+/// Reads a share typed by the user.
+///
+/// # Returns
+///
+/// The number of shares needed to unlock, the id of the split it is from, and the share.
+fn parse_share(text: &str) -> Result<(u8, u16, Share), String> {
+    let lower = SecretString::new(text.to_lowercase());
+    let mut words = lower.split_whitespace();
+    let header: Vec<&str> = words.nth(1).map(|header| header.split('-').collect()).unwrap_or_default();
+    let (Some(threshold), Some(x), Some(split)) = (match header.as_slice() {
+        [threshold, x, split] if split.len() == 4 => (threshold.parse::<u8>().ok(), x.parse::<u8>().ok(), u16::from_str_radix(split, 16).ok()),
+        _ => (None, None, None),
+    }) else {
+        return Err(format!("A share starts with `{} <needed>-<number>-<split>`, check the first two words.", SHARE_PREFIX));
+    };
+    if threshold < 2 || x == 0 {
+        return Err("The numbers at the start of the share are wrong.".to_owned());
+    }
+
+    let phrase = join_words("", words);
+    let mnemonic = match Mnemonic::parse_in_normalized(Language::English, &phrase) {
+        Ok(mnemonic) => mnemonic,
+        Err(Error::UnknownWord(i)) => return Err(format!("Word {} of the share is not a recovery word, check its spelling.", i + 1)),
+        Err(_) => return Err("The share has a mistyped, missing or swapped word, its checksum doesn't match.".to_owned()),
+    };
+
+    let entropy = SecretBytes::new(mnemonic.to_entropy());
+    let mut bytes = Vec::with_capacity(entropy.len() + 1);
+    bytes.push(x);
+    bytes.extend_from_slice(&entropy);
+    let bytes = SecretBytes::new(bytes);
+    let share = Share::try_from(&bytes[..]).map_err(|err| err.to_owned())?;
+    Ok((threshold, split, share))
+}
+
+/// Joins words with single spaces after `prefix`, straight into a `SecretString`.
+fn join_words<'a>(prefix: &str, words: impl Iterator<Item = &'a str>) -> SecretString {
+    // allocated once, so the words are never copied into a reallocation
+    let mut text = String::with_capacity(prefix.len() + 25 * 9);
+    text.push_str(prefix);
+    for word in words {
+        if !text.is_empty() {
+            text.push(' ');
+        }
+        text.push_str(word);
+    }
+    SecretString::new(text)
+}
@@ -0,0 +1,406 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::commands::get_path;
+use crate::manifest::MANIFEST;
+use crate::mycrypto::{decrypt_bytes, encrypt_bytes, read_encrypted_file};
+use crate::sealed::{file_id, read_vault_file, write_vault_file};
+use crate::secret::{SecretBytes, SecretString};
+use crate::shred::{replace_encrypted_file, shred_dir, shred_file, KEY_TABLE};
+use crate::shutdown::hold_writes;
+use crate::totp::move_secret;
+use crate::vault::{channel_file_name, new_key_id, Vault, VaultHeader};
+
+/// Where channels re-encrypted under the new key are staged, relative to the project directory.
+const STAGING_DIR: &str = "mutable.new";
+/// Where the old channels are moved while the staged ones take their place.
+const RETIRED_DIR: &str = "mutable.old";
+/// Identifies the `vault.bin` that switches the vault to the staged channels, inside
+/// the staging directory, see `commit_vault_file`.
+const COMMIT_FILE: &str = "commit";
+/// Holds what a key rotation needs to continue after an interruption, inside the
+/// staging directory, see `rotate`.
+const PENDING_FILE: &str = "pending";
+/// Records which version of each file in `mutable/` was staged, inside the staging
+/// directory, see `stage_channels`.
+const SOURCES_FILE: &str = "sources";
+
+/// Re-encrypts every channel under a new data key and switches the vault to a new header.
+///
+/// The channels are re-encrypted into `mutable.new/`, never in place. Only once every
+/// one of them is written is the new header saved, and that save (an atomic rename
+/// of `vault.bin`) is the commit point: before it the vault is entirely under the
+/// old key, after it `finish_rekey` can always complete the switch. `mutable/` is then swapped with
+/// `mutable.new/`. Progress is printed as the channels are re-encrypted.
+///
+/// Files in `mutable/` that are not in the table of contents are copied over as they are.
+///
+/// # Arguments
+///
+/// * `vault` - The unlocked vault, updated to the new header and key on success.
+/// * `new_header` - The header to switch to, with its key slots already wrapping `new_key`.
+/// * `new_key` - The new data key.
+///
+/// # Errors
+///
+/// If anything fails before the commit point, the staged channels are shredded and
+/// the vault is left as it was.
+///
+/// # Examples
+///
+/// ```
+/// let new_key = new_data_key();
+/// let mut header = vault.header.clone();
+/// header.wrap_key(Slot::User, "new password", keyfile.as_ref(), &new_key)?;
+/// header.wrap_key(Slot::Master, &master_password, None, &new_key)?;
+/// rekey(vault, header, new_key)?;
+/// ```
+pub fn rekey(vault: &mut Vault, new_header: VaultHeader, new_key: SecretBytes) -> io::Result<()> {
+    let staging = new_staging_dir()?;
+
+    let key_id = new_key_id();
+    if let Err(err) = stage_channels(vault, &new_key) {
+        let _ = shred_dir(&staging);
+        return Err(err);
+    }
+
+    commit(vault, new_header, new_key, &key_id)
+}
+
+/// Saves the header that switches the vault to the staged channels, then swaps them in.
+fn commit(vault: &mut Vault, mut new_header: VaultHeader, new_key: SecretBytes, key_id: &str) -> io::Result<()> {
+    new_header.set("key_id", key_id);
+    move_secret(&mut new_header, &vault.key, &new_key)?;
+
+    commit_vault_file(&new_header.seal(&new_key)?)?;
+    vault.header = new_header;
+    vault.key = new_key;
+
+    swap_dirs()
+}
+
+/// Empties the staging directory, or creates it, for channels to be written into
+/// before `commit_vault_file` switches the vault to them and `swap_dirs` moves them
+/// into `mutable/`.
+///
+/// # Returns
+///
+/// The path of the staging directory.
+pub fn new_staging_dir() -> io::Result<PathBuf> {
+    let staging = get_path(STAGING_DIR);
+    if staging.exists() {
+        shred_dir(&staging)?;
+    }
+    fs::create_dir(&staging)?;
+
+    Ok(staging)
+}
+
+/// Writes the `vault.bin` that switches the vault to the staged channels, the commit
+/// point of a re-key. `swap_dirs` moves them in after it.
+///
+/// `vault.bin` can't say which key the staged channels are under without being
+/// opened, so before it is written a hash of what it is about to hold is left in the
+/// staging directory, for `finish_rekey` to compare with.
+pub fn commit_vault_file(data: &[u8]) -> io::Result<()> {
+    fs::write(get_path(STAGING_DIR).join(COMMIT_FILE), file_id(data))?;
+
+    // commit point
+    write_vault_file(data)
+}
+
+/// Replaces the vault's data key with a new one, keeping its passwords.
+///
+/// Works like `rekey`, except that an interruption doesn't throw the work away:
+/// before anything is staged, the new key and its key slots are written to the
+/// staging directory, encrypted under the old key. `finish_rekey` then leaves the
+/// staged channels alone, and `resume_rotation` carries on where it stopped once
+/// the vault is unlocked again, without asking for the passwords. The header is
+/// the current one with the new slots, so settings changed since stay as they are.
+///
+/// The date is recorded in the header as `key_rotated`.
+///
+/// # Arguments
+///
+/// * `vault` - The unlocked vault, switched to the new key on success.
+/// * `slots` - A header holding only the key slots, already wrapping `new_key`.
+/// * `new_key` - The new data key.
+///
+/// # Errors
+///
+/// The staged channels are kept if anything fails, and the next unlock continues.
+/// Channels written in the meantime, still under the old key, are staged again
+/// then, see `stage_channels`.
+///
+/// # Examples
+///
+/// ```
+/// let new_key = new_data_key();
+/// let mut slots = VaultHeader::new();
+/// slots.wrap_key(Slot::User, &password, keyfile.as_ref(), &new_key)?;
+/// slots.wrap_key(Slot::Master, &master_password, None, &new_key)?;
+/// rotate(vault, slots, new_key)?;
+/// ```
+pub fn rotate(vault: &mut Vault, slots: VaultHeader, new_key: SecretBytes) -> io::Result<()> {
+    let staging = new_staging_dir()?;
+
+    let key_id = new_key_id();
+    let slot_text = slots.to_text();
+    // built in one buffer, `format!` would leave the new key behind in plain memory
+    let mut pending = SecretString::with_capacity(key_id.len() + new_key.len() * 2 + slot_text.len() + 2);
+    pending.push_str(&key_id);
+    pending.push_str("\n");
+    pending.push_hex(&new_key);
+    pending.push_str("\n");
+    pending.push_str(&slot_text);
+    fs::write(staging.join(PENDING_FILE), encrypt_bytes(&vault.key, pending.as_bytes())?)?;
+
+    continue_rotation(vault, slots, new_key, &key_id)
+}
+
+fn continue_rotation(vault: &mut Vault, slots: VaultHeader, new_key: SecretBytes, key_id: &str) -> io::Result<()> {
+    stage_channels(vault, &new_key)?;
+
+    let mut new_header = vault.header.clone();
+    new_header.merge(&slots);
+    new_header.set("key_rotated", chrono::Local::now().format("%Y-%m-%d"));
+    commit(vault, new_header, new_key, key_id)
+}
+
+/// Finishes a key rotation that was interrupted before its commit point, see `rotate`.
+///
+/// # Returns
+///
+/// Whether there was one to finish. A rotation started from another vault, one whose
+/// pending file doesn't decrypt under this vault's key, is left alone.
+///
+/// # Examples
+///
+/// ```
+/// if resume_rotation(&mut vault)? {
+///     println!("Finished rotating the data key.");
+/// }
+/// ```
+pub fn resume_rotation(vault: &mut Vault) -> io::Result<bool> {
+    let path = get_path(STAGING_DIR).join(PENDING_FILE);
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    let Ok(pending) = decrypt_bytes(&vault.key, &data) else {
+        return Ok(false);
+    };
+
+    let damaged = || io::Error::new(ErrorKind::InvalidData, "the pending key rotation is damaged");
+    let pending = SecretString::new(String::from_utf8(pending).map_err(|_| damaged())?);
+    let mut lines = pending.splitn(3, '\n');
+    let key_id = lines.next().ok_or_else(damaged)?.to_owned();
+    let new_key = lines.next()
+        .and_then(|key| hex::decode(key).ok())
+        .map(SecretBytes::new)
+        .ok_or_else(damaged)?;
+    let slots = VaultHeader::parse(lines.next().ok_or_else(damaged)?)?;
+
+    continue_rotation(vault, slots, new_key, &key_id)?;
+    Ok(true)
+}
+
+/// Writes every channel, re-encrypted under `new_key`, into the staging directory.
+///
+/// Channels already staged by an interrupted rotation are kept, see `rotate`, but
+/// only as long as they were staged from the file that is in `mutable/` now. The
+/// vault stays open on the old key after a rotation fails, and anything written
+/// since is staged again. The hash of each file staged is recorded for that in
+/// `sources`, encrypted under the new key. Staged files whose original has been
+/// deleted since are shredded, and so is the previous version of a file staged
+/// again, so a deleted channel doesn't live on in the staging directory, nor its
+/// key in a staged key table. Until the rotation is resumed they are still there,
+/// under the new key.
+fn stage_channels(vault: &Vault, new_key: &[u8]) -> io::Result<()> {
+    let staging = get_path(STAGING_DIR);
+    fs::create_dir_all(&staging)?;
+    let sources_path = staging.join(SOURCES_FILE);
+
+    // a write cut off by an interruption leaves its temporary file behind
+    for entry in fs::read_dir(&staging)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "tmp") {
+            shred_file(path)?;
+        }
+    }
+
+    let contents = read_encrypted_file(vault.channel_path("contents"), &vault.key)?;
+    let names: HashMap<OsString, &str> = contents
+        .lines()
+        .chain(["contents", KEY_TABLE, MANIFEST])
+        .map(|name| (vault.channel_path(name).file_name().unwrap_or_default().to_owned(), name))
+        .collect();
+
+    let mut sources: HashMap<String, String> = match read_encrypted_file(&sources_path, new_key) {
+        Ok(text) => text
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(staged, source)| (staged.to_owned(), source.to_owned()))
+            .collect(),
+        Err(err) if err.kind() == ErrorKind::NotFound => HashMap::new(),
+        Err(err) => return Err(err),
+    };
+
+    // each file in `mutable/` with the name it is staged under
+    let mut files = Vec::new();
+    for entry in fs::read_dir(get_path("mutable"))? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "txt") {
+            let file_name = path.file_name().unwrap_or_default();
+            let staged_name = match names.get(file_name) {
+                Some(name) => channel_file_name(new_key, name),
+                None => file_name.to_string_lossy().into_owned(),
+            };
+            files.push((path, staged_name));
+        }
+    }
+
+    for (done, (file, staged_name)) in files.iter().enumerate() {
+        print!("\rRe-encrypting channels... {}/{}", done + 1, files.len());
+        io::stdout().flush()?;
+
+        let source = file_id(&fs::read(file)?);
+        let staged = staging.join(staged_name);
+        if staged.exists() && sources.get(staged_name) == Some(&source) {
+            continue;
+        }
+
+        // an older staged version may hold something deleted since, so it is overwritten
+        match names.contains_key(file.file_name().unwrap_or_default()) {
+            true => {
+                let plaintext = read_encrypted_file(file, &vault.key)?;
+                replace_encrypted_file(&staged, new_key, &plaintext)?;
+            }
+            false => {
+                if staged.exists() {
+                    shred_file(&staged)?;
+                }
+                fs::copy(file, &staged)?;
+            }
+        }
+        sources.insert(staged_name.clone(), source);
+        write_sources(&sources_path, new_key, &sources)?;
+    }
+    println!();
+
+    // whatever was staged before but has no file in `mutable/` any more was deleted since
+    let live: HashSet<&String> = files.iter().map(|(_, staged_name)| staged_name).collect();
+    let gone: Vec<String> = sources.keys().filter(|staged| !live.contains(staged)).cloned().collect();
+    if !gone.is_empty() {
+        for staged in &gone {
+            let path = staging.join(staged);
+            if path.exists() {
+                shred_file(path)?;
+            }
+            sources.remove(staged);
+        }
+        write_sources(&sources_path, new_key, &sources)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the hashes of the files `stage_channels` staged, see there.
+fn write_sources(path: &Path, new_key: &[u8], sources: &HashMap<String, String>) -> io::Result<()> {
+    let text: String = sources
+        .iter()
+        .map(|(staged, source)| format!("{} {}\n", staged, source))
+        .collect();
+    replace_encrypted_file(path, new_key, &text)
+}
+
+/// Moves the staged channels into `mutable/` and shreds the old ones, see `shred_dir`.
+///
+/// Each step can be repeated, so it is safe to run again after being interrupted
+/// at any point.
+pub fn swap_dirs() -> io::Result<()> {
+    let live = get_path("mutable");
+    let staging = get_path(STAGING_DIR);
+    let retired = get_path(RETIRED_DIR);
+
+    // the new key is in there, encrypted under the old one
+    if staging.join(PENDING_FILE).exists() {
+        shred_file(staging.join(PENDING_FILE))?;
+    }
+
+    // left over from a swap interrupted while it was being shredded
+    if live.exists() && retired.exists() {
+        shred_dir(&retired)?;
+    }
+
+    {
+        let _writes = hold_writes();
+        if live.exists() {
+            fs::rename(&live, &retired)?;
+        }
+        fs::rename(&staging, &live)?;
+        fs::remove_file(live.join(COMMIT_FILE))?;
+        if live.join(SOURCES_FILE).exists() {
+            fs::remove_file(live.join(SOURCES_FILE))?;
+        }
+    }
+
+    // shredding takes the write guard itself, file by file
+    if retired.exists() {
+        shred_dir(&retired)?;
+    }
+
+    Ok(())
+}
+
+/// Finishes or rolls back a re-key that was interrupted, before the vault is unlocked.
+///
+/// If `vault.bin` is the one the re-key was about to write, the commit point was
+/// passed and the swap is completed. Otherwise the staged channels are shredded
+/// and the vault stays on its old key, unless they belong to a key
+/// rotation, which `resume_rotation` continues after the unlock.
+///
+/// Nothing is printed: this runs before any password is entered, and whoever is at
+/// the prompt, maybe with the duress password, mustn't learn that a password was
+/// changed or a key rotated. A rotation is reported by `resume_rotation` once the
+/// vault it belongs to is unlocked.
+///
+/// # Examples
+///
+/// ```
+/// if let Err(err) = finish_rekey() {
+///     println!("error: could not finish an interrupted re-key: {}", err);
+/// }
+/// ```
+pub fn finish_rekey() -> io::Result<()> {
+    let staging = get_path(STAGING_DIR);
+    let retired = get_path(RETIRED_DIR);
+
+    if staging.exists() {
+        let committed = fs::read_to_string(staging.join(COMMIT_FILE)).ok();
+        let current = match read_vault_file() {
+            Ok(data) => Some(file_id(&data)),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+
+        if committed.is_some() && committed == current {
+            return swap_dirs();
+        }
+        if staging.join(PENDING_FILE).exists() {
+            return Ok(());
+        }
+
+        return shred_dir(&staging);
+    }
+
+    if retired.exists() {
+        shred_dir(&retired)?;
+    }
+
+    Ok(())
+}
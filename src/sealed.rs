@@ -0,0 +1,362 @@
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use rand::{Rng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::commands::get_path;
+use crate::keyfile::Keyfile;
+use crate::mycrypto::{chaff_bytes, decrypt_unmarked, encrypt_unmarked};
+use crate::password::{derive_key, KdfParams, DEFAULT_M_COST, DEFAULT_P_COST, DEFAULT_T_COST};
+use crate::recovery::normalize_phrase;
+use crate::secret::SecretBytes;
+use crate::shutdown::hold_writes;
+use crate::vault::{subkey, Slot, DATA_KEY_LEN};
+
+/// Where the vaults are sealed, relative to the project directory.
+pub const VAULT_PATH: &str = "Immutable/vault.bin";
+
+/// How many vaults `vault.bin` has room for: the one a password opens, and the
+/// other one the duress password opens.
+pub const VAULT_COUNT: usize = 2;
+
+const SALT_LEN: usize = 16;
+/// What `encrypt_unmarked` adds to the plaintext: the nonce and the tag.
+const SEAL_OVERHEAD: usize = 12 + 16;
+/// The longest keyfile path a key slot can remember.
+const MAX_PATH_LEN: usize = 512;
+/// The data key, sealed under the key derived from the password and the keyfile.
+const INNER_LEN: usize = SEAL_OVERHEAD + DATA_KEY_LEN;
+/// What a key slot seals under the password alone: the length of the keyfile path,
+/// the path padded to `MAX_PATH_LEN`, then the inner layer.
+const OUTER_LEN: usize = 2 + MAX_PATH_LEN + INNER_LEN;
+/// A key slot: the salt, then the sealed outer layer.
+const SLOT_LEN: usize = SALT_LEN + SEAL_OVERHEAD + OUTER_LEN;
+/// A sealed vault header: the length of its text, the text, then random padding.
+const BLOCK_LEN: usize = 2048;
+const BLOCK_PAYLOAD_LEN: usize = BLOCK_LEN - SEAL_OVERHEAD;
+/// One vault's part of the file: its user slot, its master slot and its header.
+const VAULT_LEN: usize = 2 * SLOT_LEN + BLOCK_LEN;
+const FILE_LEN: usize = VAULT_COUNT * VAULT_LEN;
+
+/// Chaff files written next to the channels when `vault.bin` is made, see `write_chaff`.
+const MIN_CHAFF_FILES: usize = 3;
+const MAX_CHAFF_FILES: usize = 8;
+
+// The layout of `vault.bin`.
+//
+// There is room for two vaults, and each has the same parts at the same sizes:
+// a key slot for the login password, one for the Master password, and the vault
+// header. Everything is sealed without any marker, and the part of a vault that
+// isn't in use is filled with random bytes, so the file looks the same whether it
+// holds one vault or two, and nothing in it says which of the two was made first.
+// A password is tried against the key slots of both vaults, and the one it opens
+// decides which vault is unlocked. The vault a duress password opens works the
+// same way as any other, since on disk it is no different.
+//
+// A key slot seals the data key in two layers. The outer one is under a key derived
+// from the password, and holds the path of the vault's keyfile if it has one, so
+// the keyfile can be found again without anything about it being visible. The
+// inner one is under that key with the keyfile mixed in, see `Keyfile::mix_into`.
+// There is no room for the costs of the key derivation, key slots always use the
+// default ones.
+//
+// A vault header is sealed under a key derived from its data key.
+
+/// The bytes of a key slot in the file.
+fn slot_range(index: usize, slot: Slot) -> Range<usize> {
+    let start = index * VAULT_LEN + match slot {
+        Slot::User => 0,
+        Slot::Master => SLOT_LEN,
+    };
+    start..start + SLOT_LEN
+}
+
+/// The bytes of a vault header in the file.
+fn block_range(index: usize) -> Range<usize> {
+    let start = index * VAULT_LEN + 2 * SLOT_LEN;
+    start..start + BLOCK_LEN
+}
+
+/// The position of the vault that isn't `index`.
+pub fn other_index(index: usize) -> usize {
+    (index + 1) % VAULT_COUNT
+}
+
+/// Whether the project directory has a `vault.bin`.
+pub fn vault_file_exists() -> bool {
+    get_path(VAULT_PATH).exists()
+}
+
+/// Reads `vault.bin`.
+///
+/// # Errors
+///
+/// Returns a `NotFound` error if there is none, and an `InvalidData` error if it
+/// isn't the size every `vault.bin` is.
+pub fn read_vault_file() -> io::Result<Vec<u8>> {
+    let data = fs::read(get_path(VAULT_PATH))?;
+    if data.len() != FILE_LEN {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Immutable/vault.bin is damaged"));
+    }
+    Ok(data)
+}
+
+/// Writes `vault.bin` through a temporary file, so it is replaced in one step.
+pub fn write_vault_file(data: &[u8]) -> io::Result<()> {
+    let _writes = hold_writes();
+    let path = get_path(VAULT_PATH);
+    let temp_path = path.with_extension("tmp");
+    let mut temp_file = fs::File::create(&temp_path)?;
+    temp_file.write_all(data)?;
+    temp_file.sync_all()?;
+
+    fs::rename(temp_path, path)
+}
+
+/// Identifies what a file holds, so a re-key can tell whether `vault.bin` got its
+/// new header and whether a staged channel is still up to date, see `rekey.rs`.
+pub fn file_id(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Returns the contents of a new `vault.bin`: random bytes, room for two vaults
+/// with neither in use yet. Nothing is written, a vault is sealed into it first.
+pub fn random_vault_file() -> Vec<u8> {
+    let mut data = vec![0u8; FILE_LEN];
+    rand::thread_rng().fill_bytes(&mut data);
+    data
+}
+
+/// Writes a few chaff files to `dir`, random bytes laid out like encrypted channels,
+/// for when a new `vault.bin` is made.
+///
+/// The channel files of both vaults are indistinguishable from the chaff, so the
+/// number of files in `mutable/` doesn't tell how many vaults there are either.
+///
+/// # Arguments
+///
+/// * `dir` - `mutable/`, or the directory a migration stages the channels in.
+pub fn write_chaff(dir: &Path) -> io::Result<()> {
+    let mut rng = rand::thread_rng();
+    for _ in 0..rng.gen_range(MIN_CHAFF_FILES..=MAX_CHAFF_FILES) {
+        let mut name = [0u8; 32];
+        rng.fill_bytes(&mut name);
+        let len = rng.gen_range(20..400);
+        fs::write(dir.join(format!("{}.txt", hex::encode(name))), chaff_bytes(len))?;
+    }
+
+    Ok(())
+}
+
+/// Picks where the first vault of a new `vault.bin` goes, so its position says nothing.
+pub fn random_index() -> usize {
+    rand::thread_rng().gen_range(0..VAULT_COUNT)
+}
+
+/// Fills a vault's part of `vault.bin` with random bytes, destroying it.
+///
+/// Its channel files stay in `mutable/` as chaff, without its data key nothing can
+/// read them.
+pub fn clear_vault(index: usize) -> io::Result<()> {
+    let mut data = read_vault_file()?;
+    let start = index * VAULT_LEN;
+    rand::thread_rng().fill_bytes(&mut data[start..start + VAULT_LEN]);
+    write_vault_file(&data)
+}
+
+/// The key parameters of a key slot with the given salt.
+fn slot_params(salt: &[u8]) -> KdfParams {
+    KdfParams {
+        salt: salt.to_vec(),
+        m_cost: DEFAULT_M_COST,
+        t_cost: DEFAULT_T_COST,
+        p_cost: DEFAULT_P_COST,
+    }
+}
+
+/// Seals a data key into a new key slot.
+///
+/// # Arguments
+///
+/// * `password` - The password that will open the slot.
+/// * `keyfile` - A keyfile that will be needed besides the password, its path is
+///   sealed into the slot so it can be found again.
+/// * `data_key` - The vault's data key.
+///
+/// # Returns
+///
+/// The slot, `SLOT_LEN` bytes that can't be told apart from random ones.
+///
+/// # Errors
+///
+/// Returns an `InvalidInput` error if the keyfile's path is too long to remember.
+pub fn seal_slot(password: &str, keyfile: Option<&Keyfile>, data_key: &[u8]) -> io::Result<Vec<u8>> {
+    let params = KdfParams::generate();
+    let kek = derive_key(password, &params)?;
+
+    let path = keyfile.map(|keyfile| keyfile.path.as_bytes()).unwrap_or_default();
+    if path.len() > MAX_PATH_LEN {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "the keyfile path is too long"));
+    }
+    let inner = match keyfile {
+        Some(keyfile) => encrypt_unmarked(&keyfile.mix_into(&kek), data_key)?,
+        None => encrypt_unmarked(&kek, data_key)?,
+    };
+
+    let mut outer = SecretBytes::new(vec![0u8; OUTER_LEN]);
+    outer[..2].copy_from_slice(&(path.len() as u16).to_be_bytes());
+    outer[2..2 + path.len()].copy_from_slice(path);
+    rand::thread_rng().fill_bytes(&mut outer[2 + path.len()..2 + MAX_PATH_LEN]);
+    outer[2 + MAX_PATH_LEN..].copy_from_slice(&inner);
+
+    let mut slot = params.salt;
+    slot.extend(encrypt_unmarked(&kek, &outer)?);
+    Ok(slot)
+}
+
+/// A key slot whose outer layer a password has opened.
+pub struct OpenSlot {
+    /// The vault the slot belongs to.
+    pub index: usize,
+    pub slot: Slot,
+    /// The keyfile needed besides the password, if the vault has one.
+    pub keyfile_path: Option<String>,
+    kek: SecretBytes,
+    inner: Vec<u8>,
+}
+
+impl OpenSlot {
+    /// Opens the inner layer and returns the data key.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidInput` error if the slot needs a keyfile and none was given,
+    /// and an `InvalidData` error if the keyfile is the wrong one.
+    pub fn data_key(&self, keyfile: Option<&Keyfile>) -> io::Result<SecretBytes> {
+        let key = match (&self.keyfile_path, keyfile) {
+            (None, _) => self.kek.clone(),
+            (Some(_), Some(keyfile)) => keyfile.mix_into(&self.kek),
+            (Some(_), None) => return Err(io::Error::new(ErrorKind::InvalidInput, "a keyfile is needed")),
+        };
+        decrypt_unmarked(&key, &self.inner).map(SecretBytes::new)
+    }
+}
+
+/// Opens the outer layer of a key slot with a password.
+///
+/// # Returns
+///
+/// `None` if the password doesn't open it, or the slot isn't in use.
+pub fn open_slot(slot_bytes: &[u8], index: usize, slot: Slot, password: &str) -> Option<OpenSlot> {
+    if slot_bytes.len() != SLOT_LEN {
+        return None;
+    }
+    let kek = derive_key(password, &slot_params(&slot_bytes[..SALT_LEN])).ok()?;
+    let outer = SecretBytes::new(decrypt_unmarked(&kek, &slot_bytes[SALT_LEN..]).ok()?);
+
+    let path_len = u16::from_be_bytes([outer[0], outer[1]]) as usize;
+    if path_len > MAX_PATH_LEN {
+        return None;
+    }
+    let path = &outer[2..2 + path_len];
+    let keyfile_path = match path.is_empty() {
+        true => None,
+        false => Some(String::from_utf8(path.to_vec()).ok()?),
+    };
+
+    Some(OpenSlot { index, slot, keyfile_path, kek, inner: outer[2 + MAX_PATH_LEN..].to_vec() })
+}
+
+/// The key slot of a vault as it is in `vault.bin`.
+pub fn slot_bytes(data: &[u8], index: usize, slot: Slot) -> Vec<u8> {
+    data[slot_range(index, slot)].to_vec()
+}
+
+/// Finds the key slot a password opens, in either vault.
+///
+/// The password is tried against the user slots first, then against the master
+/// slots. A recovery phrase typed with capitals or extra spaces still opens its
+/// master slot, see `normalize_phrase`.
+///
+/// # Returns
+///
+/// The opened slot, or `None` if the password opens none of them.
+///
+/// # Examples
+///
+/// ```
+/// let data = read_vault_file()?;
+/// if let Some(open) = find_slot(&data, &password) {
+///     let key = open.data_key(None)?;
+///     let header = VaultHeader::open(&data, open.index, &key)?;
+/// }
+/// ```
+pub fn find_slot(data: &[u8], password: &str) -> Option<OpenSlot> {
+    for index in 0..VAULT_COUNT {
+        if let Some(open) = open_slot(&data[slot_range(index, Slot::User)], index, Slot::User, password) {
+            return Some(open);
+        }
+    }
+
+    let normalized = normalize_phrase(password);
+    let password = normalized.as_deref().unwrap_or(password);
+    (0..VAULT_COUNT).find_map(|index| open_slot(&data[slot_range(index, Slot::Master)], index, Slot::Master, password))
+}
+
+/// Seals a vault header into its place in `vault.bin`, together with any key slots
+/// given, and leaves the other vault as it is.
+///
+/// # Arguments
+///
+/// * `data` - `vault.bin` as it is now.
+/// * `index` - Which vault's part to replace.
+/// * `slots` - Key slots from `seal_slot` to put in, slots not given are kept.
+/// * `data_key` - The vault's data key, the header is sealed under a key derived from it.
+/// * `text` - The header's `key=value` lines.
+///
+/// # Errors
+///
+/// Returns an `InvalidInput` error if the header is too long to fit.
+pub fn seal_into(mut data: Vec<u8>, index: usize, slots: &[(Slot, Vec<u8>)], data_key: &[u8], text: &str) -> io::Result<Vec<u8>> {
+    let used = 2 + text.len();
+    if used > BLOCK_PAYLOAD_LEN {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "the vault header is too long"));
+    }
+
+    for (slot, bytes) in slots {
+        if bytes.len() != SLOT_LEN {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "a key slot has the wrong size"));
+        }
+        data[slot_range(index, *slot)].copy_from_slice(bytes);
+    }
+
+    let mut payload = SecretBytes::new(vec![0u8; BLOCK_PAYLOAD_LEN]);
+    payload[..2].copy_from_slice(&(text.len() as u16).to_be_bytes());
+    payload[2..used].copy_from_slice(text.as_bytes());
+    rand::thread_rng().fill_bytes(&mut payload[used..]);
+    data[block_range(index)].copy_from_slice(&encrypt_unmarked(&subkey(data_key, "vault header"), &payload)?);
+
+    Ok(data)
+}
+
+/// Opens a vault header sealed in `vault.bin`.
+///
+/// # Returns
+///
+/// The header's `key=value` lines.
+///
+/// # Errors
+///
+/// Returns an `InvalidData` error if the header doesn't open under the data key,
+/// because it was changed or the key is not this vault's.
+pub fn open_block(data: &[u8], index: usize, data_key: &[u8]) -> io::Result<String> {
+    let damaged = || io::Error::new(ErrorKind::InvalidData, "vault header has been modified");
+    let payload = SecretBytes::new(decrypt_unmarked(&subkey(data_key, "vault header"), &data[block_range(index)]).map_err(|_| damaged())?);
+
+    let text_len = u16::from_be_bytes([payload[0], payload[1]]) as usize;
+    let text = payload.get(2..2 + text_len).ok_or_else(damaged)?;
+    String::from_utf8(text.to_vec()).map_err(|_| damaged())
+}
@@ -0,0 +1,226 @@
+#[cfg(target_os = "linux")]
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+#[cfg(target_os = "linux")]
+use std::sync::Mutex;
+
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+/// Key material that is wiped from memory as soon as it is dropped.
+///
+/// Used for the data key, the keys derived from passwords and the legacy key. On
+/// Linux the buffer is also locked into RAM with `mlock`, to keep it out of swap.
+/// Locking is best effort: if the limit on locked memory is reached the key is kept
+/// anyway, and a hibernating system writes out locked pages too. Pages shared by
+/// several secrets stay locked until the last of them is dropped, see `LOCKED_PAGES`.
+///
+/// There is no `Display`, and `Debug` never prints the bytes, so a key can't end up
+/// in a log or an error message by accident. Comparisons run in constant time.
+///
+/// # Examples
+///
+/// ```
+/// let key = SecretBytes::new(vec![7u8; 32]);
+/// let sealed = encrypt_bytes(&key, b"hello")?;
+/// assert_eq!(format!("{:?}", key), "SecretBytes(32 bytes)");
+/// drop(key); // the 32 bytes are zeroed before the memory is freed
+/// ```
+pub struct SecretBytes(Vec<u8>);
+
+impl SecretBytes {
+    pub fn new(bytes: Vec<u8>) -> SecretBytes {
+        lock_memory(bytes.as_ptr(), bytes.capacity());
+        SecretBytes(bytes)
+    }
+
+    /// An empty secret, for a key that has been wiped.
+    pub fn empty() -> SecretBytes {
+        SecretBytes(Vec::new())
+    }
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for SecretBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl Clone for SecretBytes {
+    fn clone(&self) -> SecretBytes {
+        SecretBytes::new(self.0.clone())
+    }
+}
+
+impl PartialEq for SecretBytes {
+    fn eq(&self, other: &SecretBytes) -> bool {
+        self.0.ct_eq(&other.0).into()
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretBytes({} bytes)", self.0.len())
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        self.0.zeroize();
+        unlock_memory(self.0.as_ptr(), self.0.capacity());
+    }
+}
+
+/// A password, wiped from memory as soon as it is dropped.
+///
+/// Like `SecretBytes` it is locked into RAM on Linux, has no `Display`, and its
+/// `Debug` never shows the text. It dereferences to `str` so it can be passed
+/// wherever a `&str` is expected, but printing it has to be spelled out.
+///
+/// # Examples
+///
+/// ```
+/// let Some(password) = read_password("Enter password:\n") else { return };
+/// let keyfile = keyfile_for(&header, &password);
+/// let (slot, key) = header.unlock(&password, keyfile.as_ref())?;
+/// ```
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn new(text: String) -> SecretString {
+        lock_memory(text.as_ptr(), text.capacity());
+        SecretString(text)
+    }
+
+    /// An empty secret with room for `capacity` bytes, filled in with `push_str` and `push_hex`.
+    pub fn with_capacity(capacity: usize) -> SecretString {
+        SecretString::new(String::with_capacity(capacity))
+    }
+
+    /// Appends `text`.
+    ///
+    /// When it doesn't fit, the secret is moved to a bigger buffer and the old one is
+    /// wiped, where `String::push_str` would free the old buffer with the text still in it.
+    pub fn push_str(&mut self, text: &str) {
+        self.make_room(text.len());
+        self.0.push_str(text);
+    }
+
+    /// Appends `bytes` as lowercase hex, without the plain `String` that `hex::encode`
+    /// would leave behind.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let mut text = SecretString::with_capacity(key.len() * 2 + 1);
+    /// text.push_hex(&key);
+    /// text.push_str("\n");
+    /// ```
+    pub fn push_hex(&mut self, bytes: &[u8]) {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+        self.make_room(bytes.len() * 2);
+        for byte in bytes {
+            self.0.push(char::from(DIGITS[usize::from(byte >> 4)]));
+            self.0.push(char::from(DIGITS[usize::from(byte & 0xf)]));
+        }
+    }
+
+    fn make_room(&mut self, extra: usize) {
+        if self.0.capacity() - self.0.len() >= extra {
+            return;
+        }
+        let mut bigger = String::with_capacity((self.0.len() + extra).max(self.0.capacity() * 2));
+        bigger.push_str(&self.0);
+        // dropping the old secret wipes its buffer
+        *self = SecretString::new(bigger);
+    }
+}
+
+impl Deref for SecretString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretString(..)")
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+        unlock_memory(self.0.as_ptr(), self.0.capacity());
+    }
+}
+
+/// How many live secrets are on each locked page, by the page's address.
+///
+/// `mlock` works on whole pages and isn't counted, one `munlock` releases a page no
+/// matter how many secrets share it. So a page is only unlocked once the last
+/// secret on it is dropped.
+#[cfg(target_os = "linux")]
+static LOCKED_PAGES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+/// The addresses of the pages `len` bytes from `ptr` are on.
+#[cfg(target_os = "linux")]
+fn pages(ptr: *const u8, len: usize) -> impl Iterator<Item = usize> {
+    let page_size = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    };
+    let first = ptr as usize / page_size * page_size;
+    let last = (ptr as usize + len - 1) / page_size * page_size;
+    (first..=last).step_by(page_size)
+}
+
+/// Keeps a buffer out of swap. Failing to is not an error, the buffer is still wiped on drop.
+#[cfg(target_os = "linux")]
+fn lock_memory(ptr: *const u8, len: usize) {
+    if len == 0 {
+        return;
+    }
+    let mut locked = LOCKED_PAGES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    for page in pages(ptr, len) {
+        let count = locked.entry(page).or_insert(0);
+        if *count == 0 {
+            unsafe { libc::mlock(page as *const libc::c_void, 1) };
+        }
+        *count += 1;
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn unlock_memory(ptr: *const u8, len: usize) {
+    if len == 0 {
+        return;
+    }
+    let mut locked = LOCKED_PAGES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    for page in pages(ptr, len) {
+        let Some(count) = locked.get_mut(&page) else { continue };
+        *count -= 1;
+        if *count == 0 {
+            locked.remove(&page);
+            unsafe { libc::munlock(page as *const libc::c_void, 1) };
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lock_memory(_ptr: *const u8, _len: usize) {}
+
+#[cfg(not(target_os = "linux"))]
+fn unlock_memory(_ptr: *const u8, _len: usize) {}
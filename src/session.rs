@@ -0,0 +1,161 @@
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::PathBuf;
+
+use crate::commands::get_path;
+use crate::manifest::MANIFEST;
+use crate::mycrypto::{decrypt_bytes, is_plaintext, read_encrypted_file, write_encrypted_file};
+use crate::shred::KEY_TABLE;
+use crate::vault::Vault;
+
+/// Marks a vault as open, relative to the project directory.
+pub const LOCK_PATH: &str = "Immutable/session.lock";
+
+/// The session marker, held for as long as the app has the vault open.
+///
+/// The marker is created with `create_new`, so two sessions can't open the same
+/// vault at once, and removed again when the lock is dropped. If the app is
+/// killed the marker stays behind, which is how the next launch knows it has to
+/// check the vault for a half-finished session.
+///
+/// # Examples
+///
+/// ```
+/// let (lock, stale) = SessionLock::acquire().expect("Failed to lock vault");
+/// if let Some(previous) = stale {
+///     println!("The previous session did not close cleanly ({})", previous);
+/// }
+/// drop(lock); // marker removed
+/// ```
+pub struct SessionLock {
+    path: PathBuf,
+}
+
+impl SessionLock {
+    /// Creates the session marker.
+    ///
+    /// # Returns
+    ///
+    /// The lock, and the contents of a marker left behind by a session that did not
+    /// close cleanly, if there was one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `AlreadyExists` error if another session still has the vault open.
+    pub fn acquire() -> io::Result<(SessionLock, Option<String>)> {
+        let path = get_path(LOCK_PATH);
+
+        let stale = match fs::read_to_string(&path) {
+            Ok(previous) => {
+                let pid = previous.split_whitespace().next().and_then(|pid| pid.parse::<u32>().ok());
+                if pid.is_some_and(is_running) {
+                    return Err(io::Error::new(
+                        ErrorKind::AlreadyExists,
+                        format!("the vault is already open in another session (pid {})", pid.unwrap()),
+                    ));
+                }
+                fs::remove_file(&path)?;
+                Some(previous.trim().to_owned())
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        let started = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
+        write!(file, "{} started {}", std::process::id(), started)?;
+
+        Ok((SessionLock { path }, stale))
+    }
+}
+
+impl Drop for SessionLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Whether the process that wrote a session marker is still alive.
+#[cfg(target_os = "linux")]
+fn is_running(pid: u32) -> bool {
+    pid != std::process::id() && PathBuf::from(format!("/proc/{}", pid)).exists()
+}
+
+/// Whether the process that wrote a session marker is still alive. Without a
+/// portable way to ask, a leftover marker is always treated as stale.
+#[cfg(not(target_os = "linux"))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
+
+/// Checks the vault's files in `mutable/` for state left behind by a session that
+/// did not close cleanly, and repairs it.
+///
+/// * A `.txt` file that is still plaintext (decrypted in place by an older version
+///   that was killed before it could encrypt again) is encrypted with the data key.
+/// * A `.tmp` file is an encrypted write that was interrupted before its rename. If
+///   it decrypts, the write finished and it is renamed into place, otherwise it is
+///   deleted and the previous version of the channel is kept.
+///
+/// Only files named for this vault's channels are repaired or deleted. Every other
+/// file may belong to the other vault in `vault.bin`, which only it can tell, so it
+/// is left alone unless it decrypts under this vault's key.
+///
+/// # Arguments
+///
+/// * `vault` - The unlocked vault.
+///
+/// # Returns
+///
+/// A description of everything that was recovered, empty if the vault was clean.
+///
+/// # Examples
+///
+/// ```
+/// for line in recover(&vault).expect("Failed to check vault") {
+///     println!("{}", line);
+/// }
+/// ```
+pub fn recover(vault: &Vault) -> io::Result<Vec<String>> {
+    let contents = read_encrypted_file(vault.channel_path("contents"), &vault.key).unwrap_or_default();
+    let own: HashSet<PathBuf> = contents
+        .lines()
+        .chain(["contents", KEY_TABLE, MANIFEST])
+        .map(|name| vault.channel_path(name))
+        .collect();
+
+    let mut recovered = Vec::new();
+
+    for entry in fs::read_dir(get_path("mutable"))? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let is_own = own.contains(&path.with_extension("txt"));
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("tmp") => {
+                let data = fs::read(&path)?;
+                if decrypt_bytes(&vault.key, &data).is_ok() {
+                    fs::rename(&path, path.with_extension("txt"))?;
+                    recovered.push(format!("finished an interrupted write to {}", name));
+                } else if is_own {
+                    fs::remove_file(&path)?;
+                    recovered.push(format!("discarded an incomplete write to {}", name));
+                }
+            }
+            Some("txt") if is_own => {
+                let data = fs::read(&path)?;
+                if is_plaintext(&data) {
+                    write_encrypted_file(&path, &vault.key, &String::from_utf8_lossy(&data))?;
+                    recovered.push(format!("encrypted {} which had been left decrypted", name));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(recovered)
+}
@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use rand::RngCore;
+
+use crate::manifest::record;
+use crate::mycrypto::{decrypt_bytes, encrypt_bytes, read_encrypted_file, write_encrypted_file};
+use crate::secret::{SecretBytes, SecretString};
+use crate::shutdown::hold_writes;
+use crate::vault::{new_data_key, Vault};
+
+/// The table of per-channel keys, kept like a channel under the data key. No
+/// channel can be named this, see `get_channel_from_attributes`.
+pub const KEY_TABLE: &str = "channel keys";
+
+/// First line of a channel encrypted under a key of its own.
+const KEYED_MARKER: &str = "Keyed channel\n";
+
+/// Overwrites a file's contents with random bytes, in place, and flushes them to disk.
+fn overwrite(file: &mut File) -> io::Result<()> {
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(0))?;
+
+    let mut block = vec![0u8; 64 * 1024];
+    let mut left = len;
+    while left > 0 {
+        let chunk = left.min(block.len() as u64) as usize;
+        rand::thread_rng().fill_bytes(&mut block[..chunk]);
+        file.write_all(&block[..chunk])?;
+        left -= chunk as u64;
+    }
+    file.sync_all()
+}
+
+/// Overwrites a file with random bytes and then deletes it.
+///
+/// On a hard drive this leaves nothing of the file behind. SSDs and copy-on-write
+/// file systems may write the random bytes somewhere else and keep the old blocks
+/// around for a while, see `delete_channel` for what that leaves readable.
+///
+/// # Examples
+///
+/// ```
+/// shred_file(vault.channel_path("diary"))?;
+/// ```
+pub fn shred_file(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    let _writes = hold_writes();
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    overwrite(&mut file)?;
+    drop(file);
+
+    std::fs::remove_file(path)
+}
+
+/// Overwrites every file under `dir` with `shred_file` and removes the directories.
+pub fn shred_dir(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            shred_dir(&path)?;
+        } else {
+            shred_file(&path)?;
+        }
+    }
+
+    fs::remove_dir(dir)
+}
+
+/// Like `write_encrypted_file`, but overwrites the previous version of the file
+/// with random bytes once the new one has replaced it.
+///
+/// The old version stays open while the new one is renamed over it, so it can
+/// still be overwritten after it is gone from the directory. The replacement
+/// itself is as atomic as with `write_encrypted_file`.
+pub fn replace_encrypted_file(path: impl AsRef<Path>, key: &[u8], contents: &str) -> io::Result<()> {
+    let path = path.as_ref();
+    let previous = OpenOptions::new().write(true).open(path).ok();
+    write_encrypted_file(path, key, contents)?;
+
+    match previous {
+        Some(mut previous) => {
+            let _writes = hold_writes();
+            overwrite(&mut previous)
+        },
+        None => Ok(()),
+    }
+}
+
+/// Reads the per-channel keys, an empty table if no channel has one yet.
+fn read_key_table(vault: &Vault) -> io::Result<BTreeMap<String, SecretBytes>> {
+    let text = match read_encrypted_file(vault.channel_path(KEY_TABLE), &vault.key) {
+        Ok(text) => SecretString::new(text),
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut table = BTreeMap::new();
+    for line in text.lines().filter(|line| !line.is_empty()) {
+        let (key, name) = line.split_once(' ')
+            .and_then(|(key, name)| Some((hex::decode(key).ok()?, name)))
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "the channel key table is damaged"))?;
+        table.insert(name.to_owned(), SecretBytes::new(key));
+    }
+
+    Ok(table)
+}
+
+/// Writes the per-channel keys, overwriting the previous table so a destroyed key
+/// doesn't live on in it.
+fn write_key_table(vault: &Vault, table: &BTreeMap<String, SecretBytes>) -> io::Result<()> {
+    // built in one buffer, a `format!` per key would leave each key behind in plain memory
+    let mut text = SecretString::with_capacity(table.iter().map(|(name, key)| key.len() * 2 + name.len() + 2).sum());
+    for (name, key) in table {
+        text.push_hex(key);
+        text.push_str(" ");
+        text.push_str(name);
+        text.push_str("\n");
+    }
+    replace_encrypted_file(vault.channel_path(KEY_TABLE), &vault.key, &text)
+}
+
+/// Reads a channel, taking off the layer of its own key if it has one.
+///
+/// Every channel is encrypted under the data key. A channel written since channels
+/// got keys of their own is encrypted under its own random key inside that, as
+/// `Keyed channel` and the ciphertext in hex. Its key is kept in the key table, and
+/// deleting the channel overwrites it there along with the file.
+///
+/// # Returns
+///
+/// What the channel holds, which may still be locked with a password, see `padlock.rs`.
+///
+/// # Examples
+///
+/// ```
+/// let stored = read_stored(vault, "diary")?;
+/// if is_locked(&stored) { /* ask for its password */ }
+/// ```
+pub fn read_stored(vault: &Vault, name: &str) -> io::Result<String> {
+    let stored = read_encrypted_file(vault.channel_path(name), &vault.key)?;
+    let Some(sealed) = stored.strip_prefix(KEYED_MARKER) else {
+        return Ok(stored);
+    };
+
+    let table = read_key_table(vault)?;
+    let key = table.get(name).ok_or_else(|| {
+        io::Error::new(ErrorKind::InvalidData, "the channel's key is missing from the key table")
+    })?;
+    let sealed = hex::decode(sealed.trim())
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "the channel is damaged"))?;
+    String::from_utf8(decrypt_bytes(key, &sealed)?)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "decrypted file is not valid text"))
+}
+
+/// Writes a channel under its own key, giving it one first if it has none yet.
+///
+/// The previous version of the file is overwritten, see `replace_encrypted_file`,
+/// and the manifest is updated, see `manifest.rs`.
+pub fn write_stored(vault: &mut Vault, name: &str, contents: &str) -> io::Result<()> {
+    let mut table = read_key_table(vault)?;
+    if !table.contains_key(name) {
+        table.insert(name.to_owned(), new_data_key());
+        write_key_table(vault, &table)?;
+    }
+
+    let sealed = encrypt_bytes(&table[name], contents.as_bytes())?;
+    let stored = format!("{}{}\n", KEYED_MARKER, hex::encode(sealed));
+    replace_encrypted_file(vault.channel_path(name), &vault.key, &stored)?;
+    record(vault, &[name, KEY_TABLE])
+}
+
+/// Destroys a channel: forgets its key, then overwrites and deletes its file.
+///
+/// The key table is rewritten without the key and its previous version overwritten,
+/// like the file itself. On a hard drive that leaves nothing to decrypt. An SSD or a
+/// copy-on-write file system may keep old copies of both the file and the table, and
+/// the table is encrypted under the data key, which stays the same, so those copies
+/// can still be read with the password. The caller takes the channel out of the
+/// table of contents first, so an interruption never leaves it listed without a file.
+///
+/// # Returns
+///
+/// Whether the channel had a key of its own to destroy. One that didn't was last
+/// written before channels had keys, and old copies of it may still be readable
+/// with the vault's key.
+///
+/// # Examples
+///
+/// ```
+/// set_contents(&list_without_diary, vault);
+/// let shredded = delete_channel(vault, "diary")?;
+/// ```
+pub fn delete_channel(vault: &mut Vault, name: &str) -> io::Result<bool> {
+    let mut table = read_key_table(vault)?;
+    let had_key = table.remove(name).is_some();
+    if had_key {
+        write_key_table(vault, &table)?;
+    }
+
+    let path = vault.channel_path(name);
+    if path.exists() {
+        shred_file(path)?;
+    }
+    record(vault, &[name, KEY_TABLE])?;
+
+    Ok(had_key)
+}
@@ -0,0 +1,86 @@
+use std::fs;
+use std::io::{self, Write};
+use std::panic;
+use std::process;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::autolock::clear_screen;
+use crate::commands::get_path;
+use crate::myio::restore_echo;
+use crate::session::LOCK_PATH;
+
+/// Held by every write to the vault, so an exit can wait for a write in progress.
+static WRITES: Mutex<()> = Mutex::new(());
+
+/// Blocks the process from being closed by a signal until the guard is dropped.
+///
+/// Every write to `mutable/` or the vault header holds this guard, so Ctrl-C or
+/// closing the terminal halfway through a write waits for it to finish instead of
+/// leaving a `.tmp` file behind.
+///
+/// # Examples
+///
+/// ```
+/// let _writes = hold_writes();
+/// fs::rename(temp_path, path)?;
+/// ```
+pub fn hold_writes() -> MutexGuard<'static, ()> {
+    WRITES.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Closes the vault cleanly when the process is interrupted or panics.
+///
+/// SIGINT, SIGTERM and SIGHUP (Ctrl-C, `kill`, closing the terminal) wait for any
+/// write in progress, turn terminal echo back on if a password was being typed,
+/// remove the session marker and exit. A panic does the same after the usual panic
+/// message, without waiting, since the panicking thread may be the one holding the
+/// write guard. The data key only ever lives in memory, so once the process is gone
+/// nothing readable is left behind.
+///
+/// Must only be called once the session marker is held, otherwise the marker of
+/// another session could be removed.
+///
+/// # Errors
+///
+/// Returns an error if the signal handler could not be installed.
+///
+/// # Examples
+///
+/// ```
+/// let (_lock, stale_session) = SessionLock::acquire()?;
+/// close_on_exit().expect("Failed to install signal handler");
+/// ```
+pub fn close_on_exit() -> io::Result<()> {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        println!("\nThe vault was closed after an error, nothing was left decrypted.");
+        close(101);
+    }));
+
+    ctrlc::set_handler(|| {
+        let _writes = hold_writes();
+        println!("\nClosing the vault...");
+        close(130);
+    })
+    .map_err(io::Error::other)
+}
+
+/// Closes the app because a question went unanswered while the vault was unlocked,
+/// see `set_prompt_timeout`.
+///
+/// The screen and its scrollback are cleared first, since whatever was shown stays
+/// readable in the terminal after the app is gone.
+pub fn close_idle() -> ! {
+    clear_screen();
+    println!("No answer came within the idle timeout, the vault was closed.");
+    close(0)
+}
+
+/// Removes the session marker and exits.
+fn close(code: i32) -> ! {
+    restore_echo();
+    let _ = fs::remove_file(get_path(LOCK_PATH));
+    let _ = io::stdout().flush();
+    process::exit(code)
+}
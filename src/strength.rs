@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use bip39::Language;
+use zeroize::Zeroizing;
+
+use crate::myio::{myinput, read_password};
+use crate::secret::SecretString;
+use crate::vault::VaultHeader;
+
+/// Common passwords, one per line, most common first. Bundled so the estimate works offline.
+const WORDLIST: &str = include_str!("wordlist.txt");
+
+/// The score a new password needs unless the header sets `min_password_score`.
+pub const DEFAULT_MIN_SCORE: u8 = 3;
+
+/// What the user types to keep a password that scored too low.
+const OVERRIDE: &str = "use it anyway";
+
+const SCORE_NAMES: [&str; 5] = ["very weak", "weak", "fair", "strong", "very strong"];
+
+/// Keyboard rows, for spotting patterns like `qwerty` or `asdf`.
+const KEYBOARD_ROWS: [&str; 4] = ["1234567890", "qwertyuiop", "asdfghjkl", "zxcvbnm"];
+
+/// How a password would hold up against an attacker guessing it.
+pub struct Strength {
+    /// 0 (very weak) to 4 (very strong).
+    pub score: u8,
+    /// Roughly log2 of the number of guesses needed.
+    pub bits: f64,
+    /// Why the password is easier to guess than its length suggests.
+    pub weaknesses: Vec<String>,
+}
+
+/// A part of a password that is cheaper to guess than random characters.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Pattern {
+    Word { leet: bool },
+    Repeat,
+    Sequence,
+    Keyboard,
+    Year,
+}
+
+/// Maps every known password and word to its rank.
+///
+/// The bundled passwords are ranked by how common they are. After them come the 2048
+/// English words of the recovery phrase list, which aren't in any order of frequency,
+/// so each of them is ranked as if it were the last.
+fn ranks() -> &'static HashMap<&'static str, usize> {
+    static RANKS: OnceLock<HashMap<&'static str, usize>> = OnceLock::new();
+    RANKS.get_or_init(|| {
+        let mut ranks: HashMap<&'static str, usize> = WORDLIST
+            .lines()
+            .map(|word| word.trim())
+            .filter(|word| !word.is_empty())
+            .enumerate()
+            .map(|(rank, word)| (word, rank + 1))
+            .collect();
+
+        let words = Language::English.word_list();
+        let last = ranks.len() + words.len();
+        for word in words {
+            ranks.entry(word).or_insert(last);
+        }
+        ranks
+    })
+}
+
+/// Estimates how many guesses it would take to find a password.
+///
+/// The password is split into the parts that are cheapest to guess (a few hundred
+/// common passwords and 2048 common English words, including reversed and with
+/// `@`/`0`/`3`-style substitutions, repeated characters, runs like `abc` or `321`,
+/// keyboard rows, years), with everything else counted as random characters. The
+/// split needing the fewest guesses decides the score.
+///
+/// This is a rough check, not a match for a real cracking setup. Its lists are small
+/// and hold no names, places or rarer words, so a password built from those is rated
+/// higher than it deserves.
+///
+/// # Arguments
+///
+/// * `password` - The password to rate. It is never printed, weaknesses are
+///   described without quoting it.
+///
+/// # Returns
+///
+/// The score and what makes the password weak.
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(estimate("password1").score, 0);
+/// assert!(estimate("correct horse battery staple").score >= 3);
+/// ```
+pub fn estimate(password: &str) -> Strength {
+    let chars: Zeroizing<Vec<char>> = Zeroizing::new(password.chars().collect());
+    if chars.is_empty() {
+        return Strength { score: 0, bits: 0.0, weaknesses: vec!["it is empty".to_owned()] };
+    }
+
+    let random_bits = (charset_size(&chars) as f64).log2();
+
+    // best[j] is the cheapest way to guess the first j characters, and how it ends
+    let mut best: Vec<(f64, usize, Option<Pattern>)> = vec![(f64::INFINITY, 0, None); chars.len() + 1];
+    best[0].0 = 0.0;
+    for end in 1..=chars.len() {
+        best[end] = (best[end - 1].0 + random_bits, end - 1, None);
+        for start in 0..end.saturating_sub(2) {
+            if let Some((bits, pattern)) = match_pattern(&chars[start..end]) {
+                if best[start].0 + bits < best[end].0 {
+                    best[end] = (best[start].0 + bits, start, Some(pattern));
+                }
+            }
+        }
+    }
+    let bits = best[chars.len()].0;
+
+    let mut patterns = Vec::new();
+    let mut end = chars.len();
+    while end > 0 {
+        let (_, start, pattern) = best[end];
+        patterns.extend(pattern.map(|pattern| (pattern, end - start == chars.len())));
+        end = start;
+    }
+
+    let score = match bits {
+        bits if bits < 10.0 => 0,
+        bits if bits < 20.0 => 1,
+        bits if bits < 27.0 => 2,
+        bits if bits < 33.0 => 3,
+        _ => 4,
+    };
+
+    Strength { score, bits, weaknesses: weaknesses(&chars, &patterns, score) }
+}
+
+/// Describes what made a password weak, for the patterns `estimate` found in it.
+fn weaknesses(chars: &[char], patterns: &[(Pattern, bool)], score: u8) -> Vec<String> {
+    let mut weaknesses = Vec::new();
+    let mut note = |weakness: &str| {
+        if !weaknesses.iter().any(|known| known == weakness) {
+            weaknesses.push(weakness.to_owned());
+        }
+    };
+
+    for &(pattern, whole) in patterns {
+        match pattern {
+            Pattern::Word { .. } if whole => note("it is one of the most common passwords"),
+            Pattern::Word { .. } => note("it contains a common password or word"),
+            Pattern::Repeat => note("it repeats the same character"),
+            Pattern::Sequence => note("it contains a run like abc or 321"),
+            Pattern::Keyboard => note("it contains a row of keys like qwerty"),
+            Pattern::Year => note("it contains a year"),
+        }
+        if pattern == (Pattern::Word { leet: true }) {
+            note("swapping letters for look-alikes like @ or 0 doesn't fool guessing tools");
+        }
+    }
+    if chars.len() < 8 {
+        note("it is shorter than 8 characters");
+    }
+    if score < 4 && patterns.is_empty() && chars.len() >= 8 {
+        note("it is too short for the kinds of characters it uses, add a few more words");
+    }
+
+    weaknesses
+}
+
+/// The number of possible characters in each position, from the kinds the password uses.
+fn charset_size(chars: &[char]) -> usize {
+    let mut size = 0;
+    if chars.iter().any(|c| c.is_ascii_lowercase()) {
+        size += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_uppercase()) {
+        size += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_digit()) {
+        size += 10;
+    }
+    if chars.iter().any(|c| c.is_ascii() && !c.is_ascii_alphanumeric()) {
+        size += 33;
+    }
+    if chars.iter().any(|c| !c.is_ascii()) {
+        size += 100;
+    }
+    size
+}
+
+/// Checks whether a part of a password follows a pattern, and how many bits it costs.
+fn match_pattern(part: &[char]) -> Option<(f64, Pattern)> {
+    let len = part.len() as f64;
+    let mut candidates = Vec::new();
+
+    let lower = wiped_string(part.iter().flat_map(|c| c.to_lowercase()));
+    let caps_bits = if part.iter().any(|c| c.is_uppercase()) { 1.0 } else { 0.0 };
+    let unleet = wiped_string(lower.chars().map(unleet));
+    let reversed = wiped_string(lower.chars().rev());
+    for (word, extra_bits, leet) in [(&lower, 0.0, false), (&unleet, 1.0, true), (&reversed, 1.0, false)] {
+        if leet && unleet == lower {
+            continue;
+        }
+        if let Some(&rank) = ranks().get(word.as_str()) {
+            candidates.push(((rank as f64).log2() + caps_bits + extra_bits, Pattern::Word { leet }));
+        }
+    }
+
+    if part.iter().all(|&c| c == part[0]) {
+        candidates.push(((charset_size(&part[..1]) as f64).log2() + len.log2(), Pattern::Repeat));
+    }
+
+    let steps: Zeroizing<Vec<i64>> = Zeroizing::new(part.windows(2).map(|pair| pair[1] as i64 - pair[0] as i64).collect());
+    if part.iter().all(|c| c.is_ascii_alphanumeric()) && (steps.iter().all(|&step| step == 1) || steps.iter().all(|&step| step == -1)) {
+        let start_bits = if matches!(part[0], 'a' | 'A' | '0' | '1') { 1.0 } else { 5.0 };
+        candidates.push((start_bits + len.log2() + if steps[0] < 0 { 1.0 } else { 0.0 }, Pattern::Sequence));
+    }
+
+    if KEYBOARD_ROWS.iter().any(|row| row.contains(lower.as_str()) || row.contains(reversed.as_str())) {
+        candidates.push((5.5 + len.log2(), Pattern::Keyboard));
+    }
+
+    if part.len() == 4 && lower.parse::<u32>().is_ok_and(|year| (1900..2100).contains(&year)) {
+        candidates.push(((200f64).log2(), Pattern::Year));
+    }
+
+    candidates.into_iter().min_by(|a, b| a.0.total_cmp(&b.0))
+}
+
+/// Collects part of a password into a string that is wiped when dropped.
+///
+/// The buffer is sized up front, a `String` that grows leaves a copy behind in the
+/// buffer it outgrew.
+fn wiped_string<I: Iterator<Item = char> + Clone>(chars: I) -> Zeroizing<String> {
+    let mut text = Zeroizing::new(String::with_capacity(chars.clone().map(char::len_utf8).sum()));
+    text.extend(chars);
+    text
+}
+
+/// Undoes the usual look-alike substitutions.
+fn unleet(c: char) -> char {
+    match c {
+        '@' | '4' => 'a',
+        '3' => 'e',
+        '1' | '!' => 'i',
+        '0' => 'o',
+        '$' | '5' => 's',
+        '7' => 't',
+        c => c,
+    }
+}
+
+/// Returns the score new passwords need, `min_password_score` in the header or
+/// `DEFAULT_MIN_SCORE`.
+pub fn min_score(header: &VaultHeader) -> u8 {
+    header.get("min_password_score")
+        .and_then(|score| score.parse().ok())
+        .unwrap_or(DEFAULT_MIN_SCORE)
+        .min(4)
+}
+
+/// Asks for a new password, rates it, and has it typed a second time to confirm.
+///
+/// A password scoring below `min_score` is refused with the reasons it is weak,
+/// unless the user types `use it anyway`.
+///
+/// # Arguments
+///
+/// * `msg` - The prompt for the new password.
+/// * `min_score` - The lowest score accepted without an override.
+///
+/// # Returns
+///
+/// The new password, or `None` once stdin is closed.
+///
+/// # Examples
+///
+/// ```
+/// let Some(password) = choose_password("Choose a password:\n", DEFAULT_MIN_SCORE) else { return };
+/// let (vault, master_password) = Vault::create(&password, keyfile.as_ref(), random_index())?;
+/// ```
+pub fn choose_password(msg: &str, min_score: u8) -> Option<SecretString> {
+    loop {
+        let password = read_password(msg)?;
+
+        let strength = estimate(&password);
+        println!("Password strength: {} ({}/4, about 2^{:.0} guesses to find).", SCORE_NAMES[strength.score as usize], strength.score, strength.bits);
+        if strength.score < min_score {
+            println!("This password is too weak:");
+            for weakness in &strength.weaknesses {
+                println!("  - {}", weakness);
+            }
+            let answer = myinput(&format!("Type `{}` to keep it, or press enter to choose another:\n", OVERRIDE));
+            if answer != OVERRIDE {
+                continue;
+            }
+        }
+
+        let confirmation = read_password("Enter it again to confirm:\n")?;
+        if *password == *confirmation {
+            return Some(password);
+        }
+        println!("The passwords don't match, try again.");
+    }
+}
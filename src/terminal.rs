@@ -1,10 +1,14 @@
-use crate::myio::myinput;
-use crate::commands::{run,get_path};
+use crate::autolock::idle_input;
+use crate::commands::run;
 use crate::figlet::fig_header;
-use crate::password::get_hash;
-use std::fs;
-
-pub fn main() { 
+use crate::manifest::record;
+use crate::myio::set_prompt_timeout;
+use crate::mycrypto::write_encrypted_file;
+use crate::shred::write_stored;
+use crate::vault::Vault;
+
+pub fn main(vault: &mut Vault) { 
+    set_prompt_timeout(vault.idle_timeout());
     fig_header("Welcome!");
     println!("Use `/` commands to interact with the program, start with '/help' if you need\n");
 
@@ -12,48 +16,47 @@ pub fn main() {
     // create main.txt file with contents:
     // Title: Main board 
 
-    let file_name = get_hash("main");
-    let main_path = get_path(format!("mutable/{}.txt", file_name));
+    let main_path = vault.channel_path("main");
     if !main_path.exists() {
         println!("Main file is missing, creating it...");
-        fs::write(&main_path, "Title: Main board\n").expect("Failed to create main file");
+        write_stored(vault, "main", "Title: Main board\n").expect("Failed to create main file");
         println!("Main board is created.\n");
     }
 
-    let file_name = get_hash("contents");
-    let contents_path = get_path(format!("mutable/{}.txt", file_name));
+    let contents_path = vault.channel_path("contents");
     if !contents_path.exists() {
         println!("Table of Contents file is missing, creating it...");
-        fs::write(&contents_path, "contents (can't mod)\nmain\n").expect("Failed to create main file");
+        write_encrypted_file(&contents_path, &vault.key, "contents (can't mod)\nmain\n")
+            .and_then(|_| record(vault, &["contents"]))
+            .expect("Failed to create main file");
         println!("Table of Contents is created.\n");
     }
 
-    loop {
-
-        let input = myinput("");
+    // stops once stdin is closed, or it was closed while the vault was locked
+    while let Some(input) = idle_input(vault, |_| fig_header("Welcome back!")) {
 
         if input == "/quit" {
             break;
         }                        // break if input is quit command
 
         let mut item:char = ' ';
-        if input.len() > 0 {
+        if !input.is_empty() {
             item = input                               
                 .chars()
-                .nth(0)
+                .next()
                 .unwrap();
         }
         let item = item;
 
-        if (item != '/') | (input.len() <= 0) {                 // check if command
+        if (item != '/') | input.is_empty() {                 // check if command
             print!("\x1B[2A\x1B[0G\n/select main {}", &input);
-            run(["/select","main",&input].to_vec());            // write to document
+            run(["/select","main",&input].to_vec(), vault);            // write to document
         }else {
             let command_list: Vec<&str> = input             // run command
                 .split(" ")
                 .collect();
 
-            run(command_list);
+            run(command_list, vault);
         }           
 
     } 
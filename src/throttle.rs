@@ -0,0 +1,257 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::thread;
+use std::time::Duration;
+
+use chrono::TimeZone;
+
+use crate::commands::get_path;
+use crate::password::clear_password_files;
+use crate::sealed::VAULT_PATH;
+use crate::shred::shred_file;
+use crate::vault::{new_key_id, Vault, VaultHeader};
+
+/// Where failed unlock attempts are counted, relative to the project directory.
+pub const ATTEMPTS_PATH: &str = "Immutable/attempts.txt";
+
+/// Wrong passwords allowed before attempts start being slowed down.
+const FREE_ATTEMPTS: u32 = 3;
+/// The longest wait between two attempts, in seconds.
+const MAX_DELAY: u64 = 300;
+
+/// The count of wrong passwords entered since the vault was last unlocked.
+///
+/// Stored as `key=value` lines in `Immutable/attempts.txt`, since it has to be
+/// updated without the data key. `failed` counts the failures since either vault in
+/// `vault.bin` was last unlocked and sets the wait before the next attempt, `total`
+/// counts every failure and never goes down. Each vault's header remembers the
+/// `total` it last saw, so the failures since that vault was unlocked are known
+/// even if the other one was opened in between.
+///
+/// The limit set with `/wipeafter` is not kept here, it is sealed in the vault
+/// header where it can't be changed without the key, see `wipe_if_over_limit`.
+/// Each vault has a limit of its own.
+///
+/// To make tampering visible the file carries an `epoch` id that is also kept in
+/// the sealed vault header: a counter file that was deleted or swapped for another
+/// one is reported at the next unlock, and so is a `total` lower than a header saw.
+/// Lowering the counts by no more than the attempts made since can't be detected
+/// without the key, so it buys an attacker shorter waits and more guesses before
+/// the limit.
+///
+/// # Examples
+///
+/// ```
+/// let mut attempts = FailedAttempts::load()?;
+/// attempts.wait_before_attempt();
+/// match find_slot(&data, &password) {
+///     Some(open) => { /* open the vault, then `attempts.wipe_if_over_limit(&vault)` */ },
+///     None => attempts.wrong_password(),
+/// }
+/// ```
+pub struct FailedAttempts {
+    count: u32,
+    total: u32,
+    last_failed: Option<i64>,
+    epoch: Option<String>,
+}
+
+impl FailedAttempts {
+    /// Reads the counter. A missing file counts as no failed attempts.
+    pub fn load() -> io::Result<FailedAttempts> {
+        let text = match fs::read_to_string(get_path(ATTEMPTS_PATH)) {
+            Ok(text) => text,
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+
+        let fields: BTreeMap<&str, &str> = text
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .collect();
+
+        Ok(FailedAttempts {
+            count: fields.get("failed").and_then(|count| count.parse().ok()).unwrap_or(0),
+            total: fields.get("total").and_then(|total| total.parse().ok()).unwrap_or(0),
+            last_failed: fields.get("last_failed").and_then(|time| time.parse().ok()),
+            epoch: fields.get("epoch").map(|epoch| epoch.to_string()),
+        })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let mut text = format!("failed={}\ntotal={}\n", self.count, self.total);
+        if let Some(last_failed) = self.last_failed {
+            text.push_str(&format!("last_failed={}\n", last_failed));
+        }
+        if let Some(epoch) = &self.epoch {
+            text.push_str(&format!("epoch={}\n", epoch));
+        }
+
+        fs::write(get_path(ATTEMPTS_PATH), text)
+    }
+
+    /// How long has to pass after the last failure before the next attempt.
+    ///
+    /// The first `FREE_ATTEMPTS` failures cost nothing, after that the wait doubles
+    /// with every failure, up to `MAX_DELAY`.
+    fn delay(&self) -> Duration {
+        if self.count < FREE_ATTEMPTS {
+            return Duration::ZERO;
+        }
+        let doublings = (self.count - FREE_ATTEMPTS).min(16);
+        Duration::from_secs((1u64 << (doublings + 1)).min(MAX_DELAY))
+    }
+
+    /// Sleeps until the back-off after the last failed attempt has passed.
+    ///
+    /// The wait is measured from the time of the last failure, so restarting the
+    /// app doesn't skip it.
+    pub fn wait_before_attempt(&self) {
+        let Some(last_failed) = self.last_failed else { return };
+
+        let elapsed = (chrono::Utc::now().timestamp() - last_failed).max(0) as u64;
+        let remaining = self.delay().saturating_sub(Duration::from_secs(elapsed));
+        if !remaining.is_zero() {
+            println!("{} failed attempts, waiting {} second(s) before the next one...", self.count, remaining.as_secs());
+            thread::sleep(remaining);
+        }
+    }
+
+    /// Counts a wrong password.
+    pub fn record_failure(&mut self) -> io::Result<()> {
+        self.count += 1;
+        self.total += 1;
+        self.last_failed = Some(chrono::Utc::now().timestamp());
+        self.save()
+    }
+
+    /// Counts a wrong password like `record_failure` and tells the user.
+    pub fn wrong_password(&mut self) {
+        println!("Wrong password.");
+        self.report_failure();
+    }
+
+    /// Counts a wrong authenticator code the same way as a wrong password.
+    pub fn wrong_code(&mut self) {
+        println!("Wrong code.");
+        self.report_failure();
+    }
+
+    fn report_failure(&mut self) {
+        if let Err(err) = self.record_failure() {
+            println!("error: could not count the failed attempt: {}", err);
+        }
+    }
+
+    /// Wipes the vault instead of opening it if there have been at least as many
+    /// failed attempts since it was last unlocked as the limit set with `/wipeafter`.
+    ///
+    /// The limit is sealed in the header, so it can only be checked once a password
+    /// has opened it. Guesses past the limit are refused as usual, and the first
+    /// right one then destroys the vault, so they can't get anyone in. Opening the
+    /// other vault in between doesn't start the count over, see `FailedAttempts`.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the vault was wiped.
+    pub fn wipe_if_over_limit(&self, vault: &Vault) -> bool {
+        let limit = wipe_limit(&vault.header);
+        if limit == 0 || self.failures_since_unlock(vault).unwrap_or(self.count) < limit {
+            return false;
+        }
+
+        match wipe_vault() {
+            Ok(()) => println!("Too many failed attempts, the vault has been wiped."),
+            Err(err) => println!("error: could not wipe the vault after too many failed attempts: {}", err),
+        }
+        true
+    }
+
+    /// How many attempts failed since `vault` was last unlocked, `None` if the counter
+    /// file isn't the one its header was last unlocked with, or went down since.
+    fn failures_since_unlock(&self, vault: &Vault) -> Option<u32> {
+        if vault.header.get("attempts_epoch") != self.epoch.as_deref() {
+            return None;
+        }
+        match vault.header.get("attempts_seen").and_then(|seen| seen.parse::<u32>().ok()) {
+            Some(seen) => self.total.checked_sub(seen),
+            None => Some(self.count),
+        }
+    }
+
+    /// Describes the failed attempts since the last unlock, if there were any.
+    fn failures_notice(&self, failures: u32) -> Option<String> {
+        if failures == 0 {
+            return None;
+        }
+        let when = self.last_failed
+            .and_then(|time| chrono::Local.timestamp_opt(time, 0).single())
+            .map(|time| time.format(", the last one at %Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default();
+        Some(format!("{} failed attempt(s) since your last unlock{}.", failures, when))
+    }
+
+    /// Resets the counter after a successful unlock.
+    ///
+    /// The first time this runs, an epoch id is stored in the counter file and the
+    /// header of the vault that was unlocked, binding the two together. A vault
+    /// whose header has no epoch yet, such as the second vault in `vault.bin` the
+    /// first time it is unlocked, takes the one the counter file has. The header
+    /// also records the `total` it has seen, see `FailedAttempts`.
+    ///
+    /// # Returns
+    ///
+    /// A notice for the user if there were failed attempts since the last unlock, or
+    /// the counter file was removed, replaced or turned back.
+    pub fn reset(&mut self, vault: &mut Vault) -> io::Result<Option<String>> {
+        let expected = vault.header.get("attempts_epoch").map(|epoch| epoch.to_owned());
+
+        let notice = match self.failures_since_unlock(vault) {
+            Some(failures) => self.failures_notice(failures),
+            None if expected.is_none() => self.failures_notice(self.count),
+            None => Some("The failed attempt counter was deleted, replaced or turned back since your last unlock, someone may have tried to hide failed attempts.".to_owned()),
+        };
+
+        let epoch = self.epoch.clone().unwrap_or_else(new_key_id);
+        let seen = self.total.to_string();
+        if expected.as_ref() != Some(&epoch) || vault.header.get("attempts_seen") != Some(seen.as_str()) {
+            vault.header.set("attempts_epoch", &epoch);
+            vault.header.set("attempts_seen", &seen);
+            vault.header.save(&vault.key)?;
+        }
+
+        self.count = 0;
+        self.last_failed = None;
+        self.epoch = Some(epoch);
+        self.save()?;
+
+        Ok(notice)
+    }
+}
+
+/// How many wrong passwords in a row wipe a vault, `0` if they never do.
+pub fn wipe_limit(header: &VaultHeader) -> u32 {
+    header.get("wipe_after").and_then(|limit| limit.parse().ok()).unwrap_or(0)
+}
+
+/// Destroys the vault after too many failed attempts.
+///
+/// Shredding `vault.bin` destroys the only wrapped copies of the data keys of both
+/// vaults in it, so the channels can't be decrypted even if their files were
+/// recovered. The channel files and any password files left from a format 0 vault
+/// are shredded with it, the counter is removed, and the next launch offers to
+/// create a new vault.
+fn wipe_vault() -> io::Result<()> {
+    shred_file(get_path(VAULT_PATH))?;
+
+    for entry in fs::read_dir(get_path("mutable"))? {
+        let path = entry?.path();
+        if path.is_file() {
+            shred_file(path)?;
+        }
+    }
+    clear_password_files()?;
+    fs::remove_file(get_path(ATTEMPTS_PATH))
+}
@@ -0,0 +1,328 @@
+use std::io::{self, ErrorKind};
+
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use sha1::Sha1;
+use subtle::ConstantTimeEq;
+
+use crate::mycrypto::{decrypt_bytes, encrypt_bytes};
+use crate::myio::read_password;
+use crate::recovery::print_qr_code;
+use crate::secret::{SecretBytes, SecretString};
+use crate::vault::{subkey, Vault, VaultHeader};
+
+/// Seconds each code is valid for.
+const STEP: u64 = 30;
+/// Digits in a code.
+const DIGITS: u32 = 6;
+/// How many steps before or after the current one are still accepted, to allow for
+/// clock drift between the computer and the phone.
+const WINDOW: u64 = 1;
+/// Bytes of the shared secret, 160 bits as RFC 4226 recommends for HMAC-SHA1.
+const SECRET_LEN: usize = 20;
+/// Shown by authenticator apps next to the codes.
+const ISSUER: &str = "Cognitive Canvas";
+
+/// The current time in seconds since the Unix epoch, the clock codes are checked
+/// against. Every function that needs the time takes it, or a clock to read it from,
+/// as an argument, so this is the only place it is read.
+pub fn unix_now() -> u64 {
+    chrono::Utc::now().timestamp().max(0) as u64
+}
+
+/// Computes an HOTP value (RFC 4226) for a counter.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // dynamic truncation: the low nibble of the last byte picks four bytes
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    value % 10u32.pow(DIGITS)
+}
+
+/// Computes the TOTP code (RFC 6238, HMAC-SHA1, 30 second steps, 6 digits) for a time.
+///
+/// # Arguments
+///
+/// * `secret` - The shared secret.
+/// * `time` - Seconds since the Unix epoch.
+///
+/// # Examples
+///
+/// ```
+/// // test vector from RFC 6238, appendix B
+/// assert_eq!(code_at(b"12345678901234567890", 59), "287082");
+/// ```
+pub fn code_at(secret: &[u8], time: u64) -> String {
+    format!("{:0width$}", hotp(secret, time / STEP), width = DIGITS as usize)
+}
+
+/// Finds the time step a code belongs to, within `WINDOW` steps of `now`.
+///
+/// # Returns
+///
+/// The step the code is valid for, or `None` if it doesn't match any of them. The
+/// comparison runs in constant time.
+///
+/// # Examples
+///
+/// ```
+/// let step = matching_step(&secret, "287082", 59).unwrap();
+/// assert!(matching_step(&secret, "287082", 59 + 120).is_none());
+/// ```
+pub fn matching_step(secret: &[u8], code: &str, now: u64) -> Option<u64> {
+    let current = now / STEP;
+    let mut found = None;
+    for step in current.saturating_sub(WINDOW)..=current + WINDOW {
+        let expected = code_at(secret, step * STEP);
+        if bool::from(expected.as_bytes().ct_eq(code.as_bytes())) {
+            found = Some(step);
+        }
+    }
+    found
+}
+
+/// Builds the `otpauth://` URI that authenticator apps read from a QR code.
+pub fn provisioning_uri(secret: &[u8]) -> SecretString {
+    let issuer = ISSUER.replace(' ', "%20");
+    SecretString::new(format!(
+        "otpauth://totp/{issuer}:vault?secret={}&issuer={issuer}&algorithm=SHA1&digits={}&period={}",
+        encode_secret(secret),
+        DIGITS,
+        STEP,
+    ))
+}
+
+/// Encodes the secret in base32 without padding, the way authenticator apps take it.
+fn encode_secret(secret: &[u8]) -> String {
+    base32::encode(base32::Alphabet::Rfc4648 { padding: false }, secret)
+}
+
+/// Whether the vault asks for an authenticator code after the password.
+pub fn totp_enabled(vault: &Vault) -> bool {
+    vault.header.get("totp_secret").is_some()
+}
+
+/// Decrypts the TOTP secret stored in the header.
+///
+/// The secret is encrypted under a key derived from the data key, so it can only
+/// be read once the password has been accepted.
+fn load_secret(vault: &Vault) -> io::Result<SecretBytes> {
+    let encrypted = vault.header.require_hex("totp_secret")?;
+    decrypt_bytes(&subkey(&vault.key, "totp secret"), &encrypted).map(SecretBytes::new)
+}
+
+/// Checks a code against the vault's secret and remembers the step it was used in.
+///
+/// A code is only accepted once: the step of the last accepted code is kept in the
+/// header (`totp_last_step`), and codes from that step or earlier are refused, so a
+/// code seen over someone's shoulder can't be reused.
+///
+/// # Arguments
+///
+/// * `vault` - The unlocked vault.
+/// * `code` - The code the user typed.
+/// * `now` - The current time, see `unix_now`.
+///
+/// # Returns
+///
+/// `true` if the code was accepted.
+///
+/// # Errors
+///
+/// Returns an error if the secret can't be decrypted or the header can't be saved.
+pub fn accept_code(vault: &mut Vault, code: &str, now: u64) -> io::Result<bool> {
+    let secret = load_secret(vault)?;
+    if !use_code(&mut vault.header, &secret, code, now) {
+        return Ok(false);
+    }
+
+    vault.header.save(&vault.key)?;
+    Ok(true)
+}
+
+/// The part of `accept_code` that checks the code and moves `totp_last_step` on,
+/// without saving the header.
+fn use_code(header: &mut VaultHeader, secret: &[u8], code: &str, now: u64) -> bool {
+    let Some(step) = matching_step(secret, code.trim(), now) else {
+        return false;
+    };
+
+    let last_step: Option<u64> = header.get("totp_last_step").and_then(|step| step.parse().ok());
+    if last_step.is_some_and(|last_step| step <= last_step) {
+        return false;
+    }
+
+    header.set("totp_last_step", step);
+    true
+}
+
+/// Asks for the authenticator code after the password was accepted.
+///
+/// This is the second factor at login and after an idle lock. The vault is already
+/// unlocked at this point, since the secret the code is checked against is
+/// encrypted under the data key: the check is enforced by the app, it adds no
+/// encryption. Unlocking with the Master password skips it, which is the way back
+/// in when the phone is lost.
+///
+/// # Arguments
+///
+/// * `vault` - The vault the password unlocked.
+/// * `clock` - Returns the current time, read once the code is entered, see `unix_now`.
+///
+/// # Returns
+///
+/// `true` if the code was right. The code is read like a password, it isn't shown
+/// and is wiped once checked.
+///
+/// # Examples
+///
+/// ```
+/// if totp_enabled(&vault) && !ask_code(&mut vault, unix_now) {
+///     attempts.wrong_code();
+///     return None;
+/// }
+/// ```
+pub fn ask_code(vault: &mut Vault, clock: impl Fn() -> u64) -> bool {
+    let Some(code) = read_password("Enter the code from your authenticator app:\n") else { return false };
+    match accept_code(vault, &code, clock()) {
+        Ok(accepted) => accepted,
+        Err(err) => {
+            println!("error: could not check the code: {}", err);
+            false
+        }
+    }
+}
+
+/// Turns authenticator codes on: makes a new secret, shows it to be added to an
+/// authenticator app, and stores it once a code from the app checks out.
+///
+/// # Arguments
+///
+/// * `vault` - The unlocked vault, its header gets the encrypted secret.
+/// * `clock` - Returns the current time, read each time a code is entered, see `unix_now`.
+///
+/// # Errors
+///
+/// Returns an `Interrupted` error if the user cancelled, or an error if the header
+/// couldn't be saved.
+pub fn set_up_totp(vault: &mut Vault, clock: impl Fn() -> u64) -> io::Result<()> {
+    let mut secret = SecretBytes::new(vec![0u8; SECRET_LEN]);
+    rand::thread_rng().fill_bytes(&mut secret);
+
+    let uri = provisioning_uri(&secret);
+    println!("Scan this QR code with your authenticator app:\n");
+    print_qr_code(&uri);
+    let key = SecretString::new(encode_secret(&secret));
+    let groups: Vec<&str> = key.as_bytes().chunks(4).map(|group| std::str::from_utf8(group).unwrap_or_default()).collect();
+    println!("Or add it by hand with the key {}\n", groups.join(" "));
+
+    let step = loop {
+        let Some(code) = read_password("Enter the code your app shows to finish, or leave this empty to cancel:\n") else {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "no code was given, authenticator codes stay off"));
+        };
+        if code.is_empty() {
+            return Err(io::Error::new(ErrorKind::Interrupted, "cancelled, authenticator codes stay off"));
+        }
+        if let Some(step) = matching_step(&secret, &code, clock()) {
+            break step;
+        }
+        println!("That code doesn't match, check the time on your computer and phone and try again.");
+    };
+
+    let encrypted = encrypt_bytes(&subkey(&vault.key, "totp secret"), &secret)?;
+    vault.header.set("totp_secret", hex::encode(encrypted));
+    vault.header.set("totp_last_step", step);
+    vault.header.save(&vault.key)
+}
+
+/// Re-encrypts the TOTP secret in a header that is switching to a new data key.
+///
+/// The secret is encrypted under a key derived from the data key, so it has to move
+/// with it, see `rekey`. Does nothing if authenticator codes are off.
+pub fn move_secret(header: &mut VaultHeader, old_key: &[u8], new_key: &[u8]) -> io::Result<()> {
+    if header.get("totp_secret").is_none() {
+        return Ok(());
+    }
+
+    let secret = SecretBytes::new(decrypt_bytes(&subkey(old_key, "totp secret"), &header.require_hex("totp_secret")?)?);
+    let encrypted = encrypt_bytes(&subkey(new_key, "totp secret"), &secret)?;
+    header.set("totp_secret", hex::encode(encrypted));
+    Ok(())
+}
+
+/// Turns authenticator codes off.
+pub fn turn_off_totp(vault: &mut Vault) -> io::Result<()> {
+    vault.header.remove("totp_secret");
+    vault.header.remove("totp_last_step");
+    vault.header.save(&vault.key)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA1 secret from RFC 6238, appendix B.
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn code_at_matches_rfc_6238() {
+        // the RFC lists 8 digit codes, these are their last 6 digits
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+        for (time, code) in vectors {
+            assert_eq!(code_at(RFC_SECRET, time), code, "at {}", time);
+        }
+    }
+
+    #[test]
+    fn matching_step_allows_one_step_of_drift() {
+        let code = code_at(RFC_SECRET, 59);
+
+        assert_eq!(matching_step(RFC_SECRET, &code, 59), Some(1));
+        assert_eq!(matching_step(RFC_SECRET, &code, 0), Some(1));
+        assert_eq!(matching_step(RFC_SECRET, &code, 59 + STEP), Some(1));
+        assert_eq!(matching_step(RFC_SECRET, &code, 59 + 2 * STEP), None);
+        assert_eq!(matching_step(RFC_SECRET, &code_at(RFC_SECRET, 1111111109), 59), None);
+    }
+
+    #[test]
+    fn use_code_refuses_a_code_twice() {
+        let mut header = VaultHeader::new();
+        let first = code_at(RFC_SECRET, 59);
+        let next = code_at(RFC_SECRET, 59 + STEP);
+
+        assert!(use_code(&mut header, RFC_SECRET, &first, 59));
+        assert_eq!(header.get("totp_last_step"), Some("1"));
+        assert!(!use_code(&mut header, RFC_SECRET, &first, 59), "the same code was accepted twice");
+
+        assert!(use_code(&mut header, RFC_SECRET, &next, 59 + STEP));
+        assert!(!use_code(&mut header, RFC_SECRET, &first, 59 + STEP), "an older code was accepted after a newer one");
+        assert_eq!(header.get("totp_last_step"), Some("2"));
+    }
+
+    #[test]
+    fn move_secret_follows_the_data_key() {
+        let (old_key, new_key) = ([1u8; 32], [2u8; 32]);
+        let mut header = VaultHeader::new();
+        header.set("totp_secret", hex::encode(encrypt_bytes(&subkey(&old_key, "totp secret"), RFC_SECRET).unwrap()));
+
+        move_secret(&mut header, &old_key, &new_key).unwrap();
+        let encrypted = header.require_hex("totp_secret").unwrap();
+        assert_eq!(decrypt_bytes(&subkey(&new_key, "totp secret"), &encrypted).unwrap(), RFC_SECRET);
+        assert!(decrypt_bytes(&subkey(&old_key, "totp secret"), &encrypted).is_err());
+
+        let mut off = VaultHeader::new();
+        move_secret(&mut off, &old_key, &new_key).unwrap();
+        assert_eq!(off.get("totp_secret"), None);
+    }
+}
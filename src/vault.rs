@@ -0,0 +1,459 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::commands::get_path;
+use crate::keyfile::Keyfile;
+use crate::mycrypto::is_encrypted;
+use crate::password::{generate_master_password, get_hash};
+use crate::recovery::normalize_phrase;
+use crate::sealed::{open_block, open_slot, random_vault_file, read_vault_file, seal_into, seal_slot, slot_bytes, vault_file_exists, write_chaff, write_vault_file};
+use crate::secret::{SecretBytes, SecretString};
+
+/// Length in bytes of the data key.
+pub const DATA_KEY_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The vault format this version reads and writes, stored in the header as `format`.
+///
+/// * `0` - channels encrypted with AES-128-ECB under a fixed key and SHA256 password
+///   files, from before the vault had a header. It has no header to say so.
+/// * `1` - the header and key slots sealed into `vault.bin`, next to room for a
+///   second vault, see `sealed.rs`, and channels encrypted with AES-256-GCM under
+///   the data key, in files named with an HMAC keyed from it.
+///
+/// Older vaults are upgraded with `cognitive-canvas migrate`, see `migrate.rs`.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Seconds of inactivity before the vault locks itself, unless the header sets `idle_timeout`.
+pub const DEFAULT_IDLE_TIMEOUT: u64 = 300;
+
+/// The header describing a vault and how its data key is stored.
+///
+/// The channels in `mutable/` are encrypted with a random data key, and the header
+/// keeps that key wrapped twice: once under a key derived from the login password,
+/// once under one derived from the master password. Either password can unlock the
+/// vault, and changing a password only rewraps the data key.
+///
+/// The header's `key=value` lines and its two key slots are sealed into one of the
+/// two places for a vault in `Immutable/vault.bin`, see `sealed.rs`. Nothing about a
+/// vault is readable without one of its passwords, and the header is sealed under a
+/// key derived from the data key, so any edit to it is caught when it is opened.
+/// Key slots that were just wrapped are held as `user_slot` and `master_slot`
+/// fields until the header is saved, they are never part of its sealed text.
+///
+/// # Examples
+///
+/// ```
+/// let mut header = VaultHeader::new();
+/// header.set("idle_timeout", 600);
+/// header.save(&data_key).expect("Failed to save header");
+///
+/// let loaded = VaultHeader::open(&read_vault_file()?, header.index, &data_key)?;
+/// assert_eq!(loaded.get("idle_timeout"), Some("600"));
+/// ```
+#[derive(Clone)]
+pub struct VaultHeader {
+    fields: BTreeMap<String, String>,
+    /// Which of the vaults in `vault.bin` the header belongs to.
+    pub index: usize,
+}
+
+impl VaultHeader {
+    /// Creates an empty header, not yet written to disk.
+    pub fn new() -> VaultHeader {
+        VaultHeader { fields: BTreeMap::new(), index: 0 }
+    }
+
+    /// Opens a header sealed in `vault.bin`.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - `vault.bin`, see `read_vault_file`.
+    /// * `index` - Which of its vaults to open.
+    /// * `data_key` - That vault's data key.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidData` error if the header doesn't open under the data key.
+    pub fn open(data: &[u8], index: usize, data_key: &[u8]) -> io::Result<VaultHeader> {
+        let mut header = VaultHeader::parse(&open_block(data, index, data_key)?)?;
+        header.index = index;
+        Ok(header)
+    }
+
+    /// Parses header text made of `key=value` lines.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidData` error if a line isn't `key=value`.
+    pub fn parse(text: &str) -> io::Result<VaultHeader> {
+        let mut header = VaultHeader::new();
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let (key, value) = line.split_once('=').ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidData, format!("malformed vault header line: {}", line))
+            })?;
+            header.set(key.trim(), value.trim());
+        }
+
+        Ok(header)
+    }
+
+    /// Seals the header and any key slots wrapped since it was opened into its place
+    /// in `Immutable/vault.bin`, replacing the previous ones.
+    ///
+    /// `vault.bin` is written to a temporary file and renamed into place, so the
+    /// switch from the old header to the new one is atomic. The other vault's part of
+    /// the file is left as it was.
+    pub fn save(&self, data_key: &[u8]) -> io::Result<()> {
+        write_vault_file(&self.seal(data_key)?)
+    }
+
+    /// Returns `vault.bin` as `save` would write it, without writing it.
+    pub fn seal(&self, data_key: &[u8]) -> io::Result<Vec<u8>> {
+        self.seal_onto(read_vault_file()?, data_key)
+    }
+
+    /// Seals the header and any newly wrapped key slots into `data`, the contents of
+    /// a `vault.bin`, so more than one vault can be sealed before anything is written.
+    pub fn seal_onto(&self, data: Vec<u8>, data_key: &[u8]) -> io::Result<Vec<u8>> {
+        let mut slots = Vec::new();
+        for slot in [Slot::User, Slot::Master] {
+            if self.get(&slot.field()).is_some() {
+                slots.push((slot, self.require_hex(&slot.field())?));
+            }
+        }
+        let text: String = self.fields
+            .iter()
+            .filter(|(key, _)| !key.ends_with("_slot"))
+            .map(|(key, value)| format!("{}={}\n", key, value))
+            .collect();
+
+        seal_into(data, self.index, &slots, data_key, &text)
+    }
+
+    /// Formats the fields as the `key=value` lines `parse` reads.
+    pub fn to_text(&self) -> String {
+        self.fields
+            .iter()
+            .map(|(key, value)| format!("{}={}\n", key, value))
+            .collect()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields.get(key).map(|value| value.as_str())
+    }
+
+    pub fn set(&mut self, key: &str, value: impl ToString) {
+        self.fields.insert(key.to_owned(), value.to_string());
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.fields.remove(key);
+    }
+
+    /// Copies every field of `other` into this header, replacing fields of the same name.
+    pub fn merge(&mut self, other: &VaultHeader) {
+        for (key, value) in &other.fields {
+            self.set(key, value);
+        }
+    }
+
+    /// Like `get`, but a missing field is an `InvalidData` error.
+    pub fn require(&self, key: &str) -> io::Result<&str> {
+        self.get(key).ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidData, format!("vault header is missing `{}`", key))
+        })
+    }
+
+    /// Reads a field that holds a hex encoded byte string.
+    pub fn require_hex(&self, key: &str) -> io::Result<Vec<u8>> {
+        hex::decode(self.require(key)?).map_err(|_| {
+            io::Error::new(ErrorKind::InvalidData, format!("vault header has an invalid `{}`", key))
+        })
+    }
+
+    /// Reads a field that holds a number.
+    pub fn require_number<T: FromStr>(&self, key: &str) -> io::Result<T> {
+        self.require(key)?.parse().map_err(|_| {
+            io::Error::new(ErrorKind::InvalidData, format!("vault header has an invalid `{}`", key))
+        })
+    }
+
+    /// Wraps the data key under a password into a new key slot, see `seal_slot`.
+    ///
+    /// The slot is held in the header until it is saved. A new salt is generated
+    /// every time, so rewrapping a slot with the same password still changes it.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - Which slot to fill.
+    /// * `password` - The password that will unlock the slot.
+    /// * `keyfile` - A keyfile that will be needed besides the password, its path is
+    ///   recorded in the slot and in the slot's `_keyfile` field. `None` removes any
+    ///   keyfile the slot had.
+    /// * `data_key` - The vault's data key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let data_key = new_data_key();
+    /// let mut header = VaultHeader::new();
+    /// header.wrap_key(Slot::User, "my password", None, &data_key).unwrap();
+    /// assert_eq!(header.unwrap_key(Slot::User, "my password", None).unwrap(), data_key);
+    /// ```
+    pub fn wrap_key(&mut self, slot: Slot, password: &str, keyfile: Option<&Keyfile>, data_key: &[u8]) -> io::Result<()> {
+        let sealed = seal_slot(password, keyfile, data_key)?;
+        let keyfile_field = format!("{}_keyfile", slot.name());
+        match keyfile {
+            Some(keyfile) => self.set(&keyfile_field, &keyfile.path),
+            None => self.remove(&keyfile_field),
+        }
+
+        self.set(&slot.field(), hex::encode(sealed));
+        Ok(())
+    }
+
+    /// Unwraps the data key from one of this vault's key slots, the one held in the
+    /// header if it was wrapped since the last save, otherwise the one in `vault.bin`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidData` error if the password is wrong, and an `InvalidInput`
+    /// error if the slot needs a keyfile and none was given.
+    pub fn unwrap_key(&self, slot: Slot, password: &str, keyfile: Option<&Keyfile>) -> io::Result<SecretBytes> {
+        let sealed = match self.get(&slot.field()) {
+            Some(_) => self.require_hex(&slot.field())?,
+            None => slot_bytes(&read_vault_file()?, self.index, slot),
+        };
+
+        // a recovery phrase typed with capitals or extra spaces still unlocks
+        let normalized = match slot {
+            Slot::Master => normalize_phrase(password),
+            Slot::User => None,
+        };
+        let password = normalized.as_deref().unwrap_or(password);
+
+        open_slot(&sealed, self.index, slot, password)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "wrong password"))?
+            .data_key(keyfile)
+    }
+
+    /// Unlocks the vault with either the login password or the master password.
+    ///
+    /// `keyfile` is only used for the login password, see `keyfile_for`.
+    ///
+    /// # Returns
+    ///
+    /// The slot the password opened and the data key.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidData` error if the password opens neither slot.
+    pub fn unlock(&self, password: &str, keyfile: Option<&Keyfile>) -> io::Result<(Slot, SecretBytes)> {
+        match self.unwrap_key(Slot::User, password, keyfile) {
+            Ok(data_key) => Ok((Slot::User, data_key)),
+            Err(_) => self.unwrap_key(Slot::Master, password, None)
+                .map(|data_key| (Slot::Master, data_key)),
+        }
+    }
+}
+
+/// The two key slots of a vault. Each one holds the same data key, wrapped under
+/// a key derived from a different password.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    User,
+    Master,
+}
+
+impl Slot {
+    pub fn name(self) -> &'static str {
+        match self {
+            Slot::User => "user",
+            Slot::Master => "master",
+        }
+    }
+
+    /// The field a newly wrapped slot is held in until the header is saved.
+    fn field(self) -> String {
+        format!("{}_slot", self.name())
+    }
+}
+
+/// Derives a key for one purpose from the data key, so the data key itself is
+/// only ever used to encrypt channels.
+pub fn subkey(data_key: &[u8], purpose: &str) -> SecretBytes {
+    let mut mac = HmacSha256::new_from_slice(data_key)
+        .expect("HMAC accepts keys of any length");
+    mac.update(purpose.as_bytes());
+    SecretBytes::new(mac.finalize().into_bytes().to_vec())
+}
+
+/// Returns the keyed file name of a channel under a given data key.
+pub fn channel_file_name(data_key: &[u8], name: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(&subkey(data_key, "channel names"))
+        .expect("HMAC accepts keys of any length");
+    mac.update(name.as_bytes());
+
+    format!("{}.txt", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// What `mutable/` holds, used to tell a new vault from one that has lost its header.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ChannelState {
+    /// No channel files at all.
+    Empty,
+    /// Only files from before the vault had a header.
+    Legacy,
+    /// At least one file encrypted under a data key.
+    Sealed,
+}
+
+/// Looks at the channel files in `mutable/` without decrypting anything.
+pub fn channel_state() -> io::Result<ChannelState> {
+    let mut state = ChannelState::Empty;
+
+    for entry in fs::read_dir(get_path("mutable"))? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension().is_none_or(|ext| ext != "txt") {
+            continue;
+        }
+        if is_encrypted(&fs::read(&path)?) {
+            return Ok(ChannelState::Sealed);
+        }
+        state = ChannelState::Legacy;
+    }
+
+    Ok(state)
+}
+
+/// Generates a random identifier for a data key, stored in the header so a
+/// half-finished re-key can tell whether the new key was committed.
+pub fn new_key_id() -> String {
+    let mut key_id = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut key_id);
+    hex::encode(key_id)
+}
+
+/// Generates a new random data key, the key that actually encrypts `mutable/`.
+pub fn new_data_key() -> SecretBytes {
+    let mut data_key = SecretBytes::new(vec![0u8; DATA_KEY_LEN]);
+    rand::thread_rng().fill_bytes(&mut data_key);
+    data_key
+}
+
+/// An unlocked vault: its header and the data key recovered from it.
+pub struct Vault {
+    pub header: VaultHeader,
+    pub key: SecretBytes,
+    /// The key of the locked channel that is open right now, see `padlock.rs`.
+    pub channel_key: Option<SecretBytes>,
+}
+
+impl Vault {
+    /// Creates a new vault with a fresh data key and saves its header.
+    ///
+    /// The data key is wrapped under `password` and under a newly generated master
+    /// password. If there is no `vault.bin` yet, a new one is made around it, see
+    /// `random_vault_file`, and either way it is written once.
+    ///
+    /// # Arguments
+    ///
+    /// * `password` - The login password.
+    /// * `keyfile` - A keyfile needed besides the login password, if the user chose one.
+    /// * `index` - Where in `vault.bin` the vault goes, replacing anything there.
+    ///   `random_index` for the first vault, `other_index` for the second one.
+    ///
+    /// # Returns
+    ///
+    /// The unlocked vault and the master password, which must be shown to the user
+    /// since it is never stored in the clear.
+    pub fn create(password: &str, keyfile: Option<&Keyfile>, index: usize) -> io::Result<(Vault, SecretString)> {
+        let data = match vault_file_exists() {
+            true => read_vault_file()?,
+            false => {
+                write_chaff(&get_path("mutable"))?;
+                random_vault_file()
+            }
+        };
+        let data_key = new_data_key();
+        let master_password = generate_master_password();
+
+        let mut header = VaultHeader::new();
+        header.index = index;
+        header.wrap_key(Slot::User, password, keyfile, &data_key)?;
+        header.wrap_key(Slot::Master, &master_password, None, &data_key)?;
+        header.set("format", FORMAT_VERSION);
+        header.set("key_id", new_key_id());
+        write_vault_file(&header.seal_onto(data, &data_key)?)?;
+
+        Ok((Vault { header, key: data_key, channel_key: None }, master_password))
+    }
+
+    /// Returns how long the vault may sit idle before it locks itself, `None` if
+    /// auto-lock is turned off (`idle_timeout=0` in the header).
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        let seconds = self.header.get("idle_timeout")
+            .and_then(|seconds| seconds.parse().ok())
+            .unwrap_or(DEFAULT_IDLE_TIMEOUT);
+
+        (seconds > 0).then(|| Duration::from_secs(seconds))
+    }
+
+    /// Wipes the data key from memory. Nothing can be read or written until `unlock`
+    /// succeeds, the channels on disk are already encrypted. The key of an open
+    /// locked channel is wiped too, it has to be selected again.
+    pub fn lock(&mut self) {
+        self.key = SecretBytes::empty();
+        self.channel_key = None;
+    }
+
+    /// Unwraps the data key again after `lock`, with either password.
+    ///
+    /// # Returns
+    ///
+    /// The slot the password opened.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidData` error if the password opens neither of this vault's
+    /// key slots, or its header in `vault.bin` no longer opens under the key.
+    ///
+    /// Only this vault's own passwords unlock it again, not those of the other one.
+    pub fn unlock(&mut self, password: &str, keyfile: Option<&Keyfile>) -> io::Result<Slot> {
+        let (slot, key) = self.header.unlock(password, keyfile)?;
+        VaultHeader::open(&read_vault_file()?, self.header.index, &key)?;
+        self.key = key;
+        Ok(slot)
+    }
+
+    /// Returns the path of a channel's file in `mutable/`.
+    ///
+    /// The file name is an HMAC of the channel name under a key derived from the data
+    /// key, so without the key nobody can check whether a vault holds a channel with
+    /// a name they have guessed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let main_path = vault.channel_path("main");
+    /// let contents = read_encrypted_file(main_path, &vault.key)?;
+    /// ```
+    pub fn channel_path(&self, name: &str) -> PathBuf {
+        get_path(format!("mutable/{}", channel_file_name(&self.key, name)))
+    }
+}
+
+/// Returns the path a channel's file has in a format 0 vault, from before channel names were keyed.
+pub fn legacy_channel_path(name: &str) -> PathBuf {
+    get_path(format!("mutable/{}.txt", get_hash(name)))
+}
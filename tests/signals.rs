@@ -0,0 +1,197 @@
+//! Sends SIGINT, SIGTERM and SIGHUP to a running session and checks that it closes
+//! cleanly: the session marker is removed, and no temporary file or plaintext is
+//! left anywhere in the vault.
+#![cfg(unix)]
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const PASSWORD: &str = "correct horse battery staple";
+
+/// A running copy of the app, fed through its stdin and read through its stdout.
+struct Session {
+    child: Child,
+    stdin: ChildStdin,
+    output: Receiver<Vec<u8>>,
+    seen: String,
+}
+
+impl Session {
+    fn start(dir: &Path) -> Session {
+        let mut child = Command::new(dir.join("cognitive-canvas"))
+            .current_dir(dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start the app");
+
+        let stdin = child.stdin.take().unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+        let (sender, output) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 4096];
+            while let Ok(read) = stdout.read(&mut buffer) {
+                if read == 0 || sender.send(buffer[..read].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Session { child, stdin, output, seen: String::new() }
+    }
+
+    /// Waits until `pattern` is printed, returning everything printed up to it.
+    fn until(&mut self, pattern: &str) -> String {
+        let deadline = Instant::now() + Duration::from_secs(60);
+        while !self.seen.contains(pattern) {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.output.recv_timeout(left) {
+                Ok(chunk) => self.seen.push_str(&String::from_utf8_lossy(&chunk)),
+                Err(_) => panic!("Gave up waiting for {:?}, got {:?}", pattern, self.seen),
+            }
+        }
+
+        let end = self.seen.find(pattern).unwrap() + pattern.len();
+        let rest = self.seen.split_off(end);
+        std::mem::replace(&mut self.seen, rest)
+    }
+
+    fn send(&mut self, line: &str) {
+        writeln!(self.stdin, "{}", line).expect("Failed to write to the app");
+    }
+
+    fn signal(&self, signal: i32) {
+        // SAFETY: sends a signal to the child this session started and still owns
+        unsafe { libc::kill(self.child.id() as i32, signal) };
+    }
+
+    /// Waits for the app to exit, returning its exit code.
+    fn wait(mut self) -> Option<i32> {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return status.code();
+            }
+            if Instant::now() > deadline {
+                let _ = self.child.kill();
+                panic!("The app did not exit after the signal");
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+/// Makes a project directory with a copy of the app and an empty vault skeleton.
+fn project_dir() -> PathBuf {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    let dir = std::env::temp_dir().join(format!("cognitive-canvas-signals-{}-{}", std::process::id(), nanos));
+    fs::create_dir_all(dir.join("Immutable")).unwrap();
+    fs::create_dir_all(dir.join("mutable")).unwrap();
+
+    fs::copy(env!("CARGO_BIN_EXE_cognitive-canvas"), dir.join("cognitive-canvas")).unwrap();
+    fs::copy(Path::new(env!("CARGO_MANIFEST_DIR")).join("Immutable/help.txt"), dir.join("Immutable/help.txt")).unwrap();
+    fs::write(dir.join("Immutable/SHApassword.txt"), "").unwrap();
+    fs::write(dir.join("Immutable/SHAmasterpassword.txt"), "").unwrap();
+
+    dir
+}
+
+/// Creates a vault in `dir`, answering the recovery phrase check.
+fn create_vault(dir: &Path) {
+    let mut session = Session::start(dir);
+    session.until("(y/n)");
+    session.send("y");
+    session.until("Choose a password:");
+    session.send(PASSWORD);
+    session.until("again");
+    session.send(PASSWORD);
+    session.until("leave this empty for none:");
+    session.send("");
+    session.until("(y/n)");
+    session.send("n");
+
+    let phrase = session.until("(y/n)");
+    let words: Vec<(usize, String)> = phrase
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .windows(2)
+        .filter_map(|pair| Some((pair[0].strip_suffix('.')?.parse().ok()?, pair[1].to_owned())))
+        .collect();
+    assert_eq!(words.len(), 24, "Could not read the recovery phrase from {:?}", phrase);
+    session.send("n");
+    session.until("(y/n)");
+    session.send("n");
+    session.until("cleared.");
+    session.send("");
+
+    loop {
+        let line = session.until("\n");
+        if line.contains("written down correctly") {
+            break;
+        }
+        if let Some(rest) = line.split("word #").nth(1) {
+            let number: usize = rest.split(' ').next().unwrap().parse().unwrap();
+            let word = &words.iter().find(|(n, _)| *n == number).unwrap().1;
+            session.send(word);
+        }
+    }
+
+    session.until("Use `/`");
+    session.send("/quit");
+    session.send("/quit");
+    assert_eq!(session.wait(), Some(0));
+}
+
+/// Every file under `dir`, except the copy of the app.
+fn vault_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(vault_files(&path));
+        } else if path.file_name().is_some_and(|name| name != "cognitive-canvas") {
+            files.push(path);
+        }
+    }
+    files
+}
+
+#[test]
+fn signals_close_the_vault_cleanly() {
+    let dir = project_dir();
+    create_vault(&dir);
+
+    for (signal, name) in [(libc::SIGINT, "SIGINT"), (libc::SIGTERM, "SIGTERM"), (libc::SIGHUP, "SIGHUP")] {
+        let marker = format!("plaintext written before {}", name);
+
+        let mut session = Session::start(&dir);
+        session.until("Enter password:");
+        session.send(PASSWORD);
+        session.until("Use `/`");
+        assert!(dir.join("Immutable/session.lock").exists(), "No session marker while the vault is open");
+
+        // a message to the main board, so a channel write happens right before the signal
+        session.send(&marker);
+        session.until(&marker);
+        session.signal(signal);
+        assert_eq!(session.wait(), Some(130), "{} did not close the vault", name);
+
+        assert!(!dir.join("Immutable/session.lock").exists(), "{} left the session marker behind", name);
+        for file in vault_files(&dir) {
+            assert!(file.extension().is_none_or(|ext| ext != "tmp"), "{} left {} behind", name, file.display());
+            let data = fs::read(&file).unwrap();
+            assert!(
+                !data.windows(marker.len()).any(|window| window == marker.as_bytes()),
+                "{} left plaintext in {}", name, file.display()
+            );
+        }
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}